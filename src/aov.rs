@@ -0,0 +1,149 @@
+use crate::framebuffer::Framebuffer;
+use crate::hitable::HitRecord;
+use crate::noise::{hash, to_unit};
+use crate::ray::Ray;
+use crate::tonemap::{linear_to_srgb, to_bgra};
+use anyhow::{bail, Error, Result};
+use glm::{vec3, Vec3};
+use std::str::FromStr;
+
+// Arbitrary output variables: what the camera sees first in each pixel,
+// rendered alongside the image for compositing and denoising.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aov {
+    Albedo,
+    Normal,
+    Position,
+    Depth,
+    MaterialId,
+    ObjectId,
+    Samples,
+}
+
+impl FromStr for Aov {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(match s {
+            "albedo" => Aov::Albedo,
+            "normal" => Aov::Normal,
+            "position" => Aov::Position,
+            "depth" => Aov::Depth,
+            "material_id" => Aov::MaterialId,
+            "object_id" => Aov::ObjectId,
+            "samples" => Aov::Samples,
+            _ => bail!(
+                "unknown AOV '{}', expected \"albedo\", \"normal\", \"position\", \"depth\", \"material_id\", \"object_id\" or \"samples\"",
+                s
+            ),
+        })
+    }
+}
+
+impl Aov {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Position => "position",
+            Aov::Depth => "depth",
+            Aov::MaterialId => "material_id",
+            Aov::ObjectId => "object_id",
+            Aov::Samples => "samples",
+        }
+    }
+
+    // IDs can't be averaged, so pixels keep the ID of their first sample.
+    pub fn is_id(&self) -> bool {
+        matches!(self, Aov::MaterialId | Aov::ObjectId)
+    }
+
+    // Whole numbers, which need full floats to be stored exactly.
+    pub fn is_count(&self) -> bool {
+        self.is_id() || *self == Aov::Samples
+    }
+
+    // Everything but the sample count, which the renderer fills in per pixel
+    // instead, comes from the camera rays.
+    pub fn is_sampled(&self) -> bool {
+        *self != Aov::Samples
+    }
+
+    // The value for one camera ray, zero if it escaped. Depth is the distance
+    // along the ray, and it and the IDs fill all three channels. Only for
+    // AOVs that are sampled.
+    pub fn sample(&self, ray: &Ray, rec: Option<&HitRecord>) -> Vec3 {
+        let rec = match rec {
+            Some(rec) => rec,
+            None => return vec3(0.0, 0.0, 0.0),
+        };
+        let splat = |x: f32| vec3(x, x, x);
+        match self {
+            Aov::Albedo => rec.material.albedo(rec),
+            Aov::Normal => rec.normal,
+            Aov::Position => rec.position,
+            Aov::Depth => splat(rec.time * glm::length(&ray.direction)),
+            Aov::MaterialId => splat(rec.material.id() as f32),
+            Aov::ObjectId => splat(rec.object_id as f32),
+            Aov::Samples => unreachable!("sample counts aren't sampled"),
+        }
+    }
+
+    // Something to look at in an 8-bit image: albedo is sRGB encoded, normals
+    // are mapped from [-1, 1], positions and depth are stretched over the
+    // range in the image, each ID gets a colour of its own, and sample counts
+    // run from blue for the fewest to red for the most.
+    pub fn to_bgra(&self, framebuffer: &Framebuffer) -> Vec<u32> {
+        let pixels = &framebuffer.pixels;
+        match self {
+            Aov::Albedo => pixels
+                .iter()
+                .map(|c| to_bgra(&vec3(linear_to_srgb(c.x), linear_to_srgb(c.y), linear_to_srgb(c.z))))
+                .collect(),
+            Aov::Normal => pixels.iter().map(|n| to_bgra(&(0.5 * n + vec3(0.5, 0.5, 0.5)))).collect(),
+            Aov::Position | Aov::Depth => {
+                let low = pixels.iter().fold(vec3(f32::MAX, f32::MAX, f32::MAX), |a, p| glm::min2(&a, p));
+                let high = pixels.iter().fold(vec3(f32::MIN, f32::MIN, f32::MIN), |a, p| glm::max2(&a, p));
+                let range = glm::max2(&(high - low), &vec3(1e-6, 1e-6, 1e-6));
+                pixels.iter().map(|p| to_bgra(&(p - low).component_div(&range))).collect()
+            }
+            Aov::MaterialId | Aov::ObjectId => pixels.iter().map(|p| to_bgra(&id_colour(p.x as u32))).collect(),
+            Aov::Samples => {
+                let low = pixels.iter().map(|p| p.x).fold(f32::MAX, f32::min);
+                let high = pixels.iter().map(|p| p.x).fold(f32::MIN, f32::max);
+                let range = (high - low).max(1.0);
+                pixels.iter().map(|p| to_bgra(&heat((p.x - low) / range))).collect()
+            }
+        }
+    }
+}
+
+// Blue through cyan, green and yellow to red.
+fn heat(t: f32) -> Vec3 {
+    let ramp = [
+        vec3(0.0, 0.0, 1.0),
+        vec3(0.0, 1.0, 1.0),
+        vec3(0.0, 1.0, 0.0),
+        vec3(1.0, 1.0, 0.0),
+        vec3(1.0, 0.0, 0.0),
+    ];
+    let x = t.clamp(0.0, 1.0) * (ramp.len() - 1) as f32;
+    let i = (x as usize).min(ramp.len() - 2);
+    glm::lerp(&ramp[i], &ramp[i + 1], x - i as f32)
+}
+
+fn id_colour(id: u32) -> Vec3 {
+    if id == 0 {
+        return vec3(0.0, 0.0, 0.0);
+    }
+    let h = hash(id as u64);
+    vec3(to_unit(h), to_unit(hash(h)), to_unit(hash(h ^ 1)))
+}
+
+// A material ID that only depends on the name, so the same material keeps its
+// ID from render to render. It's never zero, and fits in 24 bits so it
+// survives being stored as a float.
+pub fn id_from_name(name: &str) -> u32 {
+    let h = name.bytes().fold(0u64, |h, b| hash(h ^ b as u64));
+    ((h >> 40) as u32).max(1)
+}
@@ -0,0 +1,339 @@
+use crate::aabb::Aabb;
+use crate::hitable::{HitRecord, Hitable};
+use crate::ray::Ray;
+use glm::vec3;
+
+const NUM_BINS: usize = 12;
+const MAX_LEAF_SIZE: usize = 4;
+// Cost of visiting a node relative to intersecting a primitive.
+const TRAVERSAL_COST: f32 = 0.125;
+// The SAH can make very lopsided splits of primitives that nearly coincide.
+// Past this depth nodes are split at the median instead, which halves them
+// each time, so with at most 2^32 primitives no path through the tree is
+// longer than the traversal stack.
+const MAX_SAH_DEPTH: usize = 32;
+const STACK_SIZE: usize = 64;
+
+// Nodes are laid out depth first, so an interior node's first child always
+// directly follows it and only the second child's index needs storing.
+#[derive(Clone, Copy)]
+struct BvhNode {
+    bounds: Aabb,
+    // Leaf: index of the first primitive. Interior: index of the second child.
+    offset: u32,
+    // Zero for interior nodes.
+    count: u16,
+    axis: u8,
+}
+
+struct PrimitiveInfo {
+    index: usize,
+    bounds: Aabb,
+    centroid: glm::Vec3,
+}
+
+pub struct Bvh<T> {
+    nodes: Vec<BvhNode>,
+    primitives: Vec<T>,
+    // Primitives with no bounding box, which are tested against every ray.
+    unbounded: Vec<T>,
+}
+
+impl<T: Hitable> Bvh<T> {
+    // Primitives are bounded over the whole of `time0` to `time1`, so moving
+    // ones can be found at any time in between.
+    pub fn new(primitives: Vec<T>, time0: f32, time1: f32) -> Self {
+        let mut infos = Vec::with_capacity(primitives.len());
+        let mut slots: Vec<Option<T>> = Vec::with_capacity(primitives.len());
+        let mut unbounded = Vec::new();
+        for p in primitives {
+            match p.bounding_box(time0, time1) {
+                Some(bounds) => {
+                    infos.push(PrimitiveInfo {
+                        index: slots.len(),
+                        bounds,
+                        centroid: bounds.centroid(),
+                    });
+                    slots.push(Some(p));
+                }
+                None => unbounded.push(p),
+            }
+        }
+
+        let mut nodes = Vec::with_capacity(2 * infos.len());
+        if !infos.is_empty() {
+            build(&mut infos, 0, 0, &mut nodes);
+        }
+
+        // Reorder the primitives to match the leaves.
+        let primitives = infos.iter().map(|info| slots[info.index].take().unwrap()).collect();
+
+        Self {
+            nodes,
+            primitives,
+            unbounded,
+        }
+    }
+}
+
+fn build(infos: &mut [PrimitiveInfo], first: usize, depth: usize, nodes: &mut Vec<BvhNode>) -> usize {
+    let bounds = infos.iter().fold(Aabb::empty(), |b, info| b.union(&info.bounds));
+    let node_index = nodes.len();
+    nodes.push(BvhNode {
+        bounds,
+        offset: first as u32,
+        count: infos.len() as u16,
+        axis: 0,
+    });
+    if infos.len() == 1 {
+        return node_index;
+    }
+
+    let centroid_bounds = infos.iter().fold(Aabb::empty(), |b, info| b.grow(&info.centroid));
+    let axis = centroid_bounds.longest_axis();
+    let (lo, hi) = (centroid_bounds.min[axis], centroid_bounds.max[axis]);
+    if hi <= lo {
+        // Every centroid coincides, so there's no useful split to be had.
+        if infos.len() <= u16::MAX as usize {
+            return node_index;
+        }
+        let mid = infos.len() / 2;
+        return split(infos, mid, first, depth, axis, node_index, nodes);
+    }
+    if depth >= MAX_SAH_DEPTH {
+        if infos.len() <= MAX_LEAF_SIZE {
+            return node_index;
+        }
+        let mid = infos.len() / 2;
+        infos.select_nth_unstable_by(mid, |a, b| a.centroid[axis].total_cmp(&b.centroid[axis]));
+        return split(infos, mid, first, depth, axis, node_index, nodes);
+    }
+
+    let bin_of = |c: f32| (((c - lo) / (hi - lo) * NUM_BINS as f32) as usize).min(NUM_BINS - 1);
+    let mut bin_counts = [0usize; NUM_BINS];
+    let mut bin_bounds = [Aabb::empty(); NUM_BINS];
+    for info in infos.iter() {
+        let b = bin_of(info.centroid[axis]);
+        bin_counts[b] += 1;
+        bin_bounds[b] = bin_bounds[b].union(&info.bounds);
+    }
+
+    // Sweep from the right to get the area and count of every right-hand
+    // side, then from the left evaluating the SAH at each bin boundary.
+    let mut right_area = [0.0f32; NUM_BINS];
+    let mut right_count = [0usize; NUM_BINS];
+    let mut acc = Aabb::empty();
+    let mut count = 0;
+    for b in (1..NUM_BINS).rev() {
+        acc = acc.union(&bin_bounds[b]);
+        count += bin_counts[b];
+        right_area[b] = acc.surface_area();
+        right_count[b] = count;
+    }
+
+    let mut best_cost = f32::MAX;
+    let mut best_bin = 1;
+    let mut acc = Aabb::empty();
+    let mut count = 0;
+    for b in 1..NUM_BINS {
+        acc = acc.union(&bin_bounds[b - 1]);
+        count += bin_counts[b - 1];
+        let cost = count as f32 * acc.surface_area() + right_count[b] as f32 * right_area[b];
+        if cost < best_cost {
+            best_cost = cost;
+            best_bin = b;
+        }
+    }
+    let best_cost = TRAVERSAL_COST + best_cost / bounds.surface_area().max(f32::MIN_POSITIVE);
+    let leaf_cost = infos.len() as f32;
+    if infos.len() <= MAX_LEAF_SIZE && leaf_cost <= best_cost {
+        return node_index;
+    }
+
+    let mut mid = partition(infos, |info| bin_of(info.centroid[axis]) < best_bin);
+    if mid == 0 || mid == infos.len() {
+        mid = infos.len() / 2;
+        infos.select_nth_unstable_by(mid, |a, b| a.centroid[axis].total_cmp(&b.centroid[axis]));
+    }
+    split(infos, mid, first, depth, axis, node_index, nodes)
+}
+
+fn split(
+    infos: &mut [PrimitiveInfo],
+    mid: usize,
+    first: usize,
+    depth: usize,
+    axis: usize,
+    node_index: usize,
+    nodes: &mut Vec<BvhNode>,
+) -> usize {
+    let (left, right) = infos.split_at_mut(mid);
+    build(left, first, depth + 1, nodes);
+    let second = build(right, first + mid, depth + 1, nodes);
+    let node = &mut nodes[node_index];
+    node.offset = second as u32;
+    node.count = 0;
+    node.axis = axis as u8;
+    node_index
+}
+
+fn partition<F: Fn(&PrimitiveInfo) -> bool>(infos: &mut [PrimitiveInfo], pred: F) -> usize {
+    let mut mid = 0;
+    for i in 0..infos.len() {
+        if pred(&infos[i]) {
+            infos.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+impl<T: Hitable> Hitable for Bvh<T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let mut closest_so_far = t_max;
+        let mut temp_rec = None;
+        for p in self.unbounded.iter() {
+            if let Some(rec) = p.hit(ray, t_min, closest_so_far) {
+                closest_so_far = rec.time;
+                temp_rec = Some(rec);
+            }
+        }
+        if self.nodes.is_empty() {
+            return temp_rec;
+        }
+
+        let inv_direction = vec3(1.0 / ray.direction.x, 1.0 / ray.direction.y, 1.0 / ray.direction.z);
+        let mut stack = [0usize; STACK_SIZE];
+        let mut stack_len = 0;
+        let mut current = 0;
+        loop {
+            let node = &self.nodes[current];
+            if node.bounds.hit(&ray.origin, &inv_direction, t_min, closest_so_far) {
+                if node.count > 0 {
+                    let start = node.offset as usize;
+                    for p in self.primitives[start..start + node.count as usize].iter() {
+                        if let Some(rec) = p.hit(ray, t_min, closest_so_far) {
+                            closest_so_far = rec.time;
+                            temp_rec = Some(rec);
+                        }
+                    }
+                } else {
+                    // Visit the nearer child first so the far one is more
+                    // likely to be culled by the closest hit.
+                    let (near, far) = if inv_direction[node.axis as usize] < 0.0 {
+                        (node.offset as usize, current + 1)
+                    } else {
+                        (current + 1, node.offset as usize)
+                    };
+                    stack[stack_len] = far;
+                    stack_len += 1;
+                    current = near;
+                    continue;
+                }
+            }
+            if stack_len == 0 {
+                break;
+            }
+            stack_len -= 1;
+            current = stack[stack_len];
+        }
+        temp_rec
+    }
+
+    // Only good for the interval the hierarchy was built over.
+    fn bounding_box(&self, _: f32, _: f32) -> Option<Aabb> {
+        if !self.unbounded.is_empty() {
+            return None;
+        }
+        self.nodes.first().map(|n| n.bounds)
+    }
+
+    fn lights<'a>(&'a self, lights: &mut Vec<&'a (dyn Hitable + Sync)>) {
+        for p in self.primitives.iter().chain(self.unbounded.iter()) {
+            p.lights(lights);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hitable::{Hitable, HitableList, TaggedObject};
+    use crate::material::Lambertian;
+    use crate::noise::{hash, to_unit};
+    use crate::ray::Ray;
+    use crate::sphere::Sphere;
+    use crate::texture::SolidColour;
+    use crate::triangle::Triangle;
+    use glm::{vec3, Vec3};
+    use std::sync::Arc;
+
+    // Numbered draws in [-1, 1), so the same scene can be made twice.
+    fn draw(n: &mut u64) -> f32 {
+        *n += 1;
+        2.0 * to_unit(hash(*n)) - 1.0
+    }
+
+    fn point(n: &mut u64, size: f32) -> Vec3 {
+        vec3(draw(n), draw(n), draw(n)) * size
+    }
+
+    // Small spheres and triangles scattered through a box, some of them
+    // bunched up in one corner.
+    fn scene() -> HitableList {
+        let material = Arc::new(Lambertian { albedo: Arc::new(SolidColour { colour: vec3(0.5, 0.5, 0.5) }) });
+        let mut n = 0;
+        let list = (0..600)
+            .map(|i| -> Box<dyn Hitable + Sync + Send> {
+                let centre = if i % 5 == 0 { point(&mut n, 0.2) + vec3(8.0, 8.0, 8.0) } else { point(&mut n, 10.0) };
+                if i % 2 == 0 {
+                    Box::new(Sphere {
+                        centre,
+                        radius: 0.1 + 0.5 * draw(&mut n).abs(),
+                        material: material.clone(),
+                    })
+                } else {
+                    Box::new(Triangle {
+                        vertices: [centre, centre + point(&mut n, 1.0), centre + point(&mut n, 1.0)],
+                        material: material.clone(),
+                    })
+                }
+            })
+            .collect();
+        HitableList { list }
+    }
+
+    #[test]
+    fn same_closest_hit_as_testing_everything() {
+        let bvh = scene().into_bvh(0.0, 1.0);
+        // Tagged the same way `into_bvh` does.
+        let everything = HitableList {
+            list: scene()
+                .list
+                .into_iter()
+                .enumerate()
+                .map(|(i, object)| -> Box<dyn Hitable + Sync + Send> {
+                    Box::new(TaggedObject {
+                        object,
+                        id: i as u32 + 1,
+                    })
+                })
+                .collect(),
+        };
+        let mut n = 1 << 32;
+        let mut hits = 0;
+        for _ in 0..20_000 {
+            let ray = Ray {
+                origin: point(&mut n, 12.0),
+                direction: point(&mut n, 1.0),
+                time: 0.0,
+                free_flight: 0.0,
+            };
+            let expected = everything.hit(&ray, 0.001, f32::MAX).map(|r| (r.time, r.object_id));
+            let found = bvh.hit(&ray, 0.001, f32::MAX).map(|r| (r.time, r.object_id));
+            assert_eq!(found, expected);
+            hits += expected.is_some() as usize;
+        }
+        // Enough of them hit something for that to mean anything.
+        assert!(hits > 2000, "{}", hits);
+    }
+}
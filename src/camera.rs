@@ -1,43 +1,284 @@
-use glm::{Vec3, vec3};
-use rand::prelude::*;
+use anyhow::{bail, Error};
+use glm::Vec3;
+use std::f32::consts::PI;
+use std::str::FromStr;
+use crate::hitable::Hitable;
 use crate::ray::Ray;
+use crate::renderer::random_in_unit_disk;
+use crate::sampler::Sampler;
 
-pub struct Camera {
+// Turns film coordinates, running from (0, 0) at the bottom left to (1, 1) at
+// the top right, into a ray. None for points the projection doesn't cover,
+// which render black.
+pub trait Camera {
+    fn get_ray(&self, sampler: &mut dyn Sampler, u: f32, v: f32) -> Option<Ray>;
+
+    // When the shutter opens and closes. Rays are cast at times in between.
+    fn shutter(&self) -> (f32, f32) {
+        (0.0, 0.0)
+    }
+}
+
+impl<T: Camera + ?Sized> Camera for Box<T> {
+    fn get_ray(&self, sampler: &mut dyn Sampler, u: f32, v: f32) -> Option<Ray> {
+        (**self).get_ray(sampler, u, v)
+    }
+
+    fn shutter(&self) -> (f32, f32) {
+        (**self).shutter()
+    }
+}
+
+// Holds the shutter of another camera open from `open` to `close`, casting
+// each of its rays at a random time in between so that moving objects blur.
+pub struct Shutter<C> {
+    pub camera: C,
+    pub open: f32,
+    pub close: f32,
+}
+
+impl<C: Camera> Camera for Shutter<C> {
+    fn get_ray(&self, sampler: &mut dyn Sampler, u: f32, v: f32) -> Option<Ray> {
+        let time = self.open + (self.close - self.open) * sampler.next_1d();
+        self.camera.get_ray(sampler, u, v).map(|ray| Ray { time, ..ray })
+    }
+
+    fn shutter(&self) -> (f32, f32) {
+        (self.open, self.close)
+    }
+}
+
+// Right-handed basis looking from `from` towards `at`: u to the right, v up
+// and w backwards.
+fn basis(from: &Vec3, at: &Vec3, up: &Vec3) -> (Vec3, Vec3, Vec3) {
+    let w = (from - at).normalize();
+    let u = glm::cross(up, &w).normalize();
+    let v = glm::cross(&w, &u);
+    (u, v, w)
+}
+
+pub struct Perspective {
     pub lower_left: Vec3,
     pub horizontal: Vec3,
     pub vertical: Vec3,
-    pub origin: Vec3
+    pub origin: Vec3,
+    pub lens_radius: f32,
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
 }
 
-impl Camera {
-    pub fn new(from: Vec3, at: Vec3, up: Vec3, vfov: f32, aspect_ratio: f32) -> Self {
-        let theta = vfov * (std::f32::consts::PI / 180.0);
+impl Perspective {
+    // Thin lens: rays leave a disk of diameter `aperture` around `from` and
+    // converge on the plane `focus_dist` in front of it. A zero aperture is a
+    // pinhole, with everything in focus.
+    pub fn new(from: Vec3, at: Vec3, up: Vec3, vfov: f32, aspect_ratio: f32, aperture: f32, focus_dist: f32) -> Self {
+        let theta = vfov * (PI / 180.0);
         let h = (theta / 2.0).tan();
         let viewport_height = 2.0 * h;
         let viewport_width = aspect_ratio * viewport_height;
-        let focal_length = 1.0;
 
-        let w = (from - at).normalize();
-        let u = glm::cross(&up, &w).normalize();
-        let v = glm::cross(&w, &u);
+        let (u, v, w) = basis(&from, &at, &up);
         
         let origin = from;
-        let horizontal = viewport_width * u;
-        let vertical = viewport_height * v;
-        let lower_left = origin - horizontal / 2.0 - vertical / 2.0 - w;
+        let horizontal = focus_dist * viewport_width * u;
+        let vertical = focus_dist * viewport_height * v;
+        let lower_left = origin - horizontal / 2.0 - vertical / 2.0 - focus_dist * w;
 
         Self {
             origin,
             horizontal,
             vertical,
             lower_left,
+            lens_radius: aperture / 2.0,
+            u,
+            v,
+            w,
+        }
+    }
+}
+
+impl Camera for Perspective {
+    fn get_ray(&self, sampler: &mut dyn Sampler, u: f32, v: f32) -> Option<Ray> {
+        let offset = if self.lens_radius > 0.0 {
+            let d = self.lens_radius * random_in_unit_disk(sampler);
+            d.x * self.u + d.y * self.v
+        } else {
+            Vec3::zeros()
+        };
+        Some(Ray {
+            origin: self.origin + offset,
+            direction: self.lower_left + u * self.horizontal + v * self.vertical - self.origin - offset,
+            time: 0.0,
+            free_flight: 0.0,
+        })
+    }
+}
+
+// Parallel rays from a window `height` units tall centred on `from`, for
+// elevations and plans where lines shouldn't converge.
+pub struct Orthographic {
+    pub lower_left: Vec3,
+    pub horizontal: Vec3,
+    pub vertical: Vec3,
+    pub direction: Vec3,
+}
+
+impl Orthographic {
+    pub fn new(from: Vec3, at: Vec3, up: Vec3, height: f32, aspect_ratio: f32) -> Self {
+        let (u, v, w) = basis(&from, &at, &up);
+        let horizontal = aspect_ratio * height * u;
+        let vertical = height * v;
+        Self {
+            lower_left: from - horizontal / 2.0 - vertical / 2.0,
+            horizontal,
+            vertical,
+            direction: -w,
+        }
+    }
+}
+
+impl Camera for Orthographic {
+    fn get_ray(&self, _: &mut dyn Sampler, u: f32, v: f32) -> Option<Ray> {
+        Some(Ray {
+            origin: self.lower_left + u * self.horizontal + v * self.vertical,
+            direction: self.direction,
+            time: 0.0,
+            free_flight: 0.0,
+        })
+    }
+}
+
+// Equidistant fisheye, where the angle from the view direction grows linearly
+// out to `fov` across the height of the image. Corners beyond the image
+// circle are left black.
+pub struct Fisheye {
+    pub origin: Vec3,
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
+    pub fov: f32,
+    pub aspect_ratio: f32,
+}
+
+impl Fisheye {
+    pub fn new(from: Vec3, at: Vec3, up: Vec3, fov: f32, aspect_ratio: f32) -> Self {
+        let (u, v, w) = basis(&from, &at, &up);
+        Self {
+            origin: from,
+            u,
+            v,
+            w,
+            fov: fov * (PI / 180.0),
+            aspect_ratio,
         }
     }
+}
+
+impl Camera for Fisheye {
+    fn get_ray(&self, _: &mut dyn Sampler, u: f32, v: f32) -> Option<Ray> {
+        let x = (2.0 * u - 1.0) * self.aspect_ratio;
+        let y = 2.0 * v - 1.0;
+        let r = (x * x + y * y).sqrt();
+        if r > 1.0 {
+            return None;
+        }
+        let theta = r * self.fov / 2.0;
+        let phi = y.atan2(x);
+        Some(Ray {
+            origin: self.origin,
+            direction: theta.sin() * (phi.cos() * self.u + phi.sin() * self.v) - theta.cos() * self.w,
+            time: 0.0,
+            free_flight: 0.0,
+        })
+    }
+}
+
+// The full sphere of directions around `from`, with longitude across the
+// image and latitude up it, centred on `at`. Best with a 2:1 image.
+pub struct Equirectangular {
+    pub origin: Vec3,
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
+}
+
+impl Equirectangular {
+    pub fn new(from: Vec3, at: Vec3, up: Vec3) -> Self {
+        let (u, v, w) = basis(&from, &at, &up);
+        Self { origin: from, u, v, w }
+    }
+}
 
-    pub fn get_ray(&self, _: &mut ThreadRng, u: f32, v: f32) -> Ray {
-        Ray {
+impl Camera for Equirectangular {
+    fn get_ray(&self, _: &mut dyn Sampler, u: f32, v: f32) -> Option<Ray> {
+        let phi = (u - 0.5) * 2.0 * PI;
+        let theta = (v - 0.5) * PI;
+        Some(Ray {
             origin: self.origin,
-            direction: self.lower_left + u * self.horizontal + v * self.vertical - self.origin
+            direction: theta.cos() * (phi.sin() * self.u - phi.cos() * self.w) + theta.sin() * self.v,
+            time: 0.0,
+            free_flight: 0.0,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    Perspective,
+    Orthographic,
+    Fisheye,
+    Equirectangular,
+}
+
+impl FromStr for Projection {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(match s {
+            "perspective" => Projection::Perspective,
+            "orthographic" => Projection::Orthographic,
+            "fisheye" => Projection::Fisheye,
+            "equirectangular" => Projection::Equirectangular,
+            _ => bail!(
+                "unknown projection '{}', expected \"perspective\", \"orthographic\", \"fisheye\" or \"equirectangular\"",
+                s
+            ),
+        })
+    }
+}
+
+impl Projection {
+    // A pinhole camera with this projection. Orthographic views are sized to
+    // match what a perspective one would see at `at`, and fisheyes cover a
+    // hemisphere.
+    pub fn camera(&self, from: Vec3, at: Vec3, up: Vec3, vfov: f32, aspect_ratio: f32) -> Box<dyn Camera + Sync + Send> {
+        let distance = glm::distance(&from, &at);
+        match self {
+            Projection::Perspective => Box::new(Perspective::new(from, at, up, vfov, aspect_ratio, 0.0, distance)),
+            Projection::Orthographic => {
+                let height = orthographic_height(vfov, distance);
+                Box::new(Orthographic::new(from, at, up, height, aspect_ratio))
+            }
+            Projection::Fisheye => Box::new(Fisheye::new(from, at, up, 180.0, aspect_ratio)),
+            Projection::Equirectangular => Box::new(Equirectangular::new(from, at, up)),
         }
     }
-}
\ No newline at end of file
+}
+
+// The height of a perspective camera's view `distance` in front of it.
+pub fn orthographic_height(vfov: f32, distance: f32) -> f32 {
+    2.0 * (vfov * (PI / 180.0) / 2.0).tan() * distance
+}
+
+// Focuses on whatever is in the middle of the frame, by casting a ray from
+// `from` towards `at`. None if that ray escapes the scene.
+pub fn autofocus<T: Hitable>(from: &Vec3, at: &Vec3, world: &T) -> Option<f32> {
+    let ray = Ray {
+        origin: *from,
+        direction: (at - from).normalize(),
+        time: 0.0,
+        free_flight: 0.0,
+    };
+    world.hit(&ray, 0.001, f32::MAX).map(|rec| rec.time)
+}
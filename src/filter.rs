@@ -0,0 +1,279 @@
+use anyhow::{bail, Error};
+use glm::{vec3, Vec3};
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+// How samples are weighted into the pixels around them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterKind {
+    Box,
+    Tent,
+    Gaussian,
+    Mitchell,
+    Lanczos,
+}
+
+impl FromStr for FilterKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(match s {
+            "box" => FilterKind::Box,
+            "tent" => FilterKind::Tent,
+            "gaussian" => FilterKind::Gaussian,
+            "mitchell" => FilterKind::Mitchell,
+            "lanczos" => FilterKind::Lanczos,
+            _ => bail!(
+                "unknown filter '{}', expected \"box\", \"tent\", \"gaussian\", \"mitchell\" or \"lanczos\"",
+                s
+            ),
+        })
+    }
+}
+
+impl FilterKind {
+    // In pixels. A box half a pixel wide keeps every sample in its own pixel.
+    pub fn default_radius(&self) -> f32 {
+        match self {
+            FilterKind::Box => 0.5,
+            FilterKind::Tent => 1.0,
+            FilterKind::Gaussian => 1.5,
+            FilterKind::Mitchell => 2.0,
+            FilterKind::Lanczos => 3.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Filter {
+    pub kind: FilterKind,
+    pub radius: f32,
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-5 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+// Mitchell and Netravali's cubic with B = C = 1/3, over [0, 2].
+fn mitchell(x: f32) -> f32 {
+    let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+    let x2 = x * x;
+    let x3 = x2 * x;
+    if x < 1.0 {
+        ((12.0 - 9.0 * b - 6.0 * c) * x3 + (-18.0 + 12.0 * b + 6.0 * c) * x2 + (6.0 - 2.0 * b)) / 6.0
+    } else if x < 2.0 {
+        ((-b - 6.0 * c) * x3 + (6.0 * b + 30.0 * c) * x2 + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0
+    } else {
+        0.0
+    }
+}
+
+impl Filter {
+    // The weight of a sample `dx` across and `dy` down from a pixel's centre.
+    // Mitchell and Lanczos go negative in places, which is what sharpens.
+    pub fn eval(&self, dx: f32, dy: f32) -> f32 {
+        self.eval_1d(dx) * self.eval_1d(dy)
+    }
+
+    // The total weight over the filter's area, which is about what a pixel's
+    // weights add up to for each sample per pixel.
+    pub fn integral(&self) -> f32 {
+        let steps = 256;
+        let dx = 2.0 * self.radius / steps as f32;
+        let area: f32 = (0..steps).map(|i| self.eval_1d(-self.radius + (i as f32 + 0.5) * dx) * dx).sum();
+        area * area
+    }
+
+    fn eval_1d(&self, x: f32) -> f32 {
+        // Half open, so a sample right on the edge between two boxes only
+        // counts towards one of them.
+        if x < -self.radius || x >= self.radius {
+            return 0.0;
+        }
+        let x = x.abs();
+        match self.kind {
+            FilterKind::Box => 1.0,
+            FilterKind::Tent => 1.0 - x / self.radius,
+            // Shifted down so it reaches zero at the edge, rather than
+            // stopping short.
+            FilterKind::Gaussian => {
+                let sigma = self.radius / 3.0;
+                let gaussian = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                gaussian(x) - gaussian(self.radius)
+            }
+            FilterKind::Mitchell => mitchell(2.0 * x / self.radius),
+            FilterKind::Lanczos => sinc(x) * sinc(x / self.radius),
+        }
+    }
+}
+
+// Filtered radiance for a rectangle of pixels, summed over the samples
+// landing near each one along with their weights. The samples landing in
+// each pixel are also added up unweighted, for when the weights cancel out.
+// Pixels outside the image are never written to.
+pub struct Film {
+    pub filter: Filter,
+    pub width: usize,
+    pub height: usize,
+    // The top left pixel this covers, and how far it goes.
+    pub left: isize,
+    pub top: isize,
+    pub columns: usize,
+    pub rows: usize,
+    pub sums: Vec<Vec3>,
+    pub weights: Vec<f32>,
+    pub box_sums: Vec<Vec3>,
+    pub counts: Vec<u32>,
+    integral: f32,
+}
+
+impl Film {
+    pub fn new(filter: Filter, width: usize, height: usize, left: isize, top: isize, columns: usize, rows: usize) -> Self {
+        Self {
+            filter,
+            width,
+            height,
+            left,
+            top,
+            columns,
+            rows,
+            sums: vec![vec3(0.0, 0.0, 0.0); columns * rows],
+            weights: vec![0.0; columns * rows],
+            box_sums: vec![vec3(0.0, 0.0, 0.0); columns * rows],
+            counts: vec![0; columns * rows],
+            integral: filter.integral(),
+        }
+    }
+
+    // Adds radiance `c` from a sample at `x` across and `y` down the image,
+    // in pixels, to every pixel whose centre is within the filter's reach.
+    pub fn splat(&mut self, x: f32, y: f32, c: &Vec3) {
+        let filter = self.filter;
+        let (row, column) = (y.floor() as isize - self.top, x.floor() as isize - self.left);
+        if row >= 0 && row < self.rows as isize && column >= 0 && column < self.columns as isize {
+            let k = row as usize * self.columns + column as usize;
+            self.box_sums[k] += c;
+            self.counts[k] += 1;
+        }
+        let first_row = (self.top.max(0) as f32).max((y - 0.5 - filter.radius).ceil()) as isize;
+        let last_row = ((self.top + self.rows as isize).min(self.height as isize) - 1)
+            .min((y - 0.5 + filter.radius).floor() as isize);
+        let first_column = (self.left.max(0) as f32).max((x - 0.5 - filter.radius).ceil()) as isize;
+        let last_column = ((self.left + self.columns as isize).min(self.width as isize) - 1)
+            .min((x - 0.5 + filter.radius).floor() as isize);
+        for row in first_row..=last_row {
+            for column in first_column..=last_column {
+                let weight = filter.eval(x - (column as f32 + 0.5), y - (row as f32 + 0.5));
+                if weight != 0.0 {
+                    let k = (row - self.top) as usize * self.columns + (column - self.left) as usize;
+                    self.sums[k] += weight * c;
+                    self.weights[k] += weight;
+                }
+            }
+        }
+    }
+
+    // Adds in a film covering some of the same pixels.
+    pub fn merge(&mut self, other: &Film) {
+        for row in 0..other.rows {
+            let y = other.top + row as isize - self.top;
+            if y < 0 || y >= self.rows as isize {
+                continue;
+            }
+            for column in 0..other.columns {
+                let x = other.left + column as isize - self.left;
+                if x < 0 || x >= self.columns as isize {
+                    continue;
+                }
+                let (k, l) = (y as usize * self.columns + x as usize, row * other.columns + column);
+                self.sums[k] += other.sums[l];
+                self.weights[k] += other.weights[l];
+                self.box_sums[k] += other.box_sums[l];
+                self.counts[k] += other.counts[l];
+            }
+        }
+    }
+
+    // The filtered value of the pixel `k` places in, black if nothing landed
+    // there. The negative lobes of Mitchell and Lanczos can ring below zero
+    // next to bright edges, which is clamped away rather than written out as
+    // negative radiance. With only a few samples they can also cancel the
+    // weights out almost entirely, blowing the pixel up, so when the weights
+    // come to well under what that many samples should give, the plain
+    // average of the samples in the pixel is used instead.
+    pub fn get(&self, k: usize) -> Vec3 {
+        let (weight, count) = (self.weights[k], self.counts[k]);
+        let value = if count > 0 && weight < 0.25 * self.integral * count as f32 {
+            self.box_sums[k] / count as f32
+        } else if weight != 0.0 {
+            self.sums[k] / weight
+        } else {
+            vec3(0.0, 0.0, 0.0)
+        };
+        glm::max(&value, 0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise::{hash, to_unit};
+
+    const FILTERS: [FilterKind; 5] = [
+        FilterKind::Box,
+        FilterKind::Tent,
+        FilterKind::Gaussian,
+        FilterKind::Mitchell,
+        FilterKind::Lanczos,
+    ];
+
+    // One sample at a random spot in each pixel of a `size` square image,
+    // with radiance from `c`.
+    fn one_sample_per_pixel(kind: FilterKind, size: usize, c: impl Fn(u64) -> Vec3) -> Film {
+        let filter = Filter {
+            kind,
+            radius: kind.default_radius(),
+        };
+        let mut film = Film::new(filter, size, size, 0, 0, size, size);
+        for k in 0..(size * size) as u64 {
+            let (x, y) = ((k as usize % size) as f32, (k as usize / size) as f32);
+            film.splat(x + to_unit(hash(2 * k)), y + to_unit(hash(2 * k + 1)), &c(k));
+        }
+        film
+    }
+
+    #[test]
+    fn constant_radiance_stays_constant() {
+        for kind in FILTERS {
+            let film = one_sample_per_pixel(kind, 24, |_| vec3(0.5, 0.25, 1.0));
+            for k in 0..24 * 24 {
+                let error = glm::length(&(film.get(k) - vec3(0.5, 0.25, 1.0)));
+                assert!(error < 1e-4, "{:?} gave {:?} at {}", kind, film.get(k), k);
+            }
+        }
+    }
+
+    // Negative lobes can cancel the weights out, but the pixels shouldn't
+    // come out far brighter than anything that landed near them.
+    #[test]
+    fn sparse_samples_dont_blow_up() {
+        for kind in FILTERS {
+            let film = one_sample_per_pixel(kind, 64, |k| vec3(1.0, 1.0, 1.0) * to_unit(hash(k ^ 0x5eed)));
+            for k in 0..64 * 64 {
+                assert!(film.get(k).max() <= 4.0, "{:?} gave {:?} at {}", kind, film.get(k), k);
+            }
+        }
+    }
+
+    #[test]
+    fn integrals() {
+        let integral = |kind, radius| Filter { kind, radius }.integral();
+        assert!((integral(FilterKind::Box, 0.5) - 1.0).abs() < 1e-3);
+        assert!((integral(FilterKind::Tent, 1.0) - 1.0).abs() < 1e-3);
+        assert!((integral(FilterKind::Mitchell, 2.0) - 1.0).abs() < 1e-3);
+    }
+}
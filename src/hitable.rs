@@ -1,11 +1,52 @@
+use crate::aabb::Aabb;
+use crate::bvh::Bvh;
 use crate::material::Material;
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 
-use glm::Vec3;
+use glm::{vec2, vec3, Vec2, Vec3};
 use std::sync::Arc;
 
 pub trait Hitable {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
+    // Covers everywhere the object goes between `time0` and `time1`.
+    fn bounding_box(&self, time0: f32, time1: f32) -> Option<Aabb>;
+
+    // Adds every emissive primitive to `lights`. Only primitives that stay
+    // put should, since light sampling doesn't take time into account.
+    fn lights<'a>(&'a self, _lights: &mut Vec<&'a (dyn Hitable + Sync)>) {}
+
+    // Light sampling, for primitives that add themselves in `lights`. The pdf
+    // is over solid angle as seen from `origin`.
+    fn pdf(&self, _origin: &Vec3, _direction: &Vec3) -> f32 {
+        0.0
+    }
+
+    fn sample_direction(&self, _origin: &Vec3, _sampler: &mut dyn Sampler) -> Vec3 {
+        vec3(0.0, 0.0, 1.0)
+    }
+}
+
+impl<T: Hitable + ?Sized> Hitable for Box<T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        (**self).hit(ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self, time0: f32, time1: f32) -> Option<Aabb> {
+        (**self).bounding_box(time0, time1)
+    }
+
+    fn lights<'a>(&'a self, lights: &mut Vec<&'a (dyn Hitable + Sync)>) {
+        (**self).lights(lights)
+    }
+
+    fn pdf(&self, origin: &Vec3, direction: &Vec3) -> f32 {
+        (**self).pdf(origin, direction)
+    }
+
+    fn sample_direction(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        (**self).sample_direction(origin, sampler)
+    }
 }
 
 pub struct HitRecord {
@@ -14,6 +55,9 @@ pub struct HitRecord {
     pub normal: Vec3,
     pub material: Arc<dyn Material + Sync>,
     pub front_face: bool,
+    pub uv: Vec2,
+    // Set by `TaggedObject`, zero otherwise.
+    pub object_id: u32,
 }
 
 impl HitRecord {
@@ -30,21 +74,73 @@ impl HitRecord {
             normal,
             material,
             front_face,
+            uv: vec2(0.0, 0.0),
+            object_id: 0,
         }
     }
 
     pub fn set_face_normal(&mut self, ray: &Ray, outward_normal: &Vec3) {
         self.front_face = glm::dot(&ray.direction, outward_normal) < 0.0;
         self.normal = if self.front_face {
-            outward_normal.clone()
+            *outward_normal
         } else {
-            -outward_normal.clone()
+            -*outward_normal
         };
     }
 }
 
 pub struct HitableList {
-    pub list: Vec<Box<dyn Hitable + Sync>>,
+    pub list: Vec<Box<dyn Hitable + Sync + Send>>,
+}
+
+impl HitableList {
+    // The hierarchy is only valid for rays cast between `time0` and `time1`.
+    // Objects are numbered from one, in list order, for the object ID pass.
+    pub fn into_bvh(self, time0: f32, time1: f32) -> Bvh<Box<dyn Hitable + Sync + Send>> {
+        let list = self
+            .list
+            .into_iter()
+            .enumerate()
+            .map(|(i, object)| -> Box<dyn Hitable + Sync + Send> {
+                Box::new(TaggedObject {
+                    object,
+                    id: i as u32 + 1,
+                })
+            })
+            .collect();
+        Bvh::new(list, time0, time1)
+    }
+}
+
+// Stamps an ID on every hit with the object inside. Tags further out win, as
+// they're applied last.
+pub struct TaggedObject {
+    pub object: Box<dyn Hitable + Sync + Send>,
+    pub id: u32,
+}
+
+impl Hitable for TaggedObject {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let mut rec = self.object.hit(ray, t_min, t_max)?;
+        rec.object_id = self.id;
+        Some(rec)
+    }
+
+    fn bounding_box(&self, time0: f32, time1: f32) -> Option<Aabb> {
+        self.object.bounding_box(time0, time1)
+    }
+
+    fn lights<'a>(&'a self, lights: &mut Vec<&'a (dyn Hitable + Sync)>) {
+        self.object.lights(lights)
+    }
+
+    fn pdf(&self, origin: &Vec3, direction: &Vec3) -> f32 {
+        self.object.pdf(origin, direction)
+    }
+
+    fn sample_direction(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        self.object.sample_direction(origin, sampler)
+    }
 }
 
 impl Hitable for HitableList {
@@ -65,4 +161,16 @@ impl Hitable for HitableList {
             None
         }
     }
+
+    fn bounding_box(&self, time0: f32, time1: f32) -> Option<Aabb> {
+        self.list
+            .iter()
+            .try_fold(Aabb::empty(), |b, h| h.bounding_box(time0, time1).map(|hb| b.union(&hb)))
+    }
+
+    fn lights<'a>(&'a self, lights: &mut Vec<&'a (dyn Hitable + Sync)>) {
+        for h in self.list.iter() {
+            h.lights(lights);
+        }
+    }
 }
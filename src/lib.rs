@@ -0,0 +1,28 @@
+#[macro_use]
+extern crate log;
+extern crate nalgebra_glm as glm;
+
+pub mod aabb;
+pub mod aov;
+pub mod bvh;
+pub mod camera;
+pub mod filter;
+pub mod framebuffer;
+pub mod hitable;
+pub mod material;
+pub mod medium;
+pub mod mesh;
+pub mod noise;
+pub mod obj;
+pub mod onb;
+pub mod output;
+pub mod ray;
+pub mod renderer;
+pub mod sampler;
+pub mod scene;
+pub mod sphere;
+pub mod texture;
+pub mod tile;
+pub mod tonemap;
+pub mod transform;
+pub mod triangle;
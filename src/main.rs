@@ -2,44 +2,118 @@
 extern crate log;
 extern crate nalgebra_glm as glm;
 
-mod camera;
-mod hitable;
-mod material;
-mod ray;
-mod renderer;
-mod sphere;
-
-use anyhow::Result;
+use anyhow::{bail, Result};
 use glm::vec3;
-use material::{Dielectric, Lambertian, Metal};
 use minifb::{Window, WindowOptions};
+use rand::prelude::*;
 use std::env;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
+use structopt::StructOpt;
 
-use crate::camera::Camera;
-use crate::hitable::HitableList;
-use crate::sphere::Sphere;
-
-fn main() -> Result<()> {
-    if env::var("RUST_LOG").is_err() {
-        env::set_var("RUST_LOG", "trace")
-    }
-    pretty_env_logger::init();
+use rray::aov::Aov;
+use rray::camera::Projection;
+use rray::filter::{Filter, FilterKind};
+use rray::hitable::{Hitable, HitableList};
+use rray::material::{Dielectric, Lambertian, Metal};
+use rray::obj;
+use rray::output::{self, Precision};
+use rray::renderer::{self, Background, RenderSettings};
+use rray::sampler::SamplerKind;
+use rray::scene::{self, Scene};
+use rray::sphere::Sphere;
+use rray::texture::SolidColour;
+use rray::tile::TileOrder;
+use rray::tonemap::{Operator, ToneMapper};
 
-    
-    let r: f32 = (std::f32::consts::PI / 4.0).cos();
-    const ASPECT_RATIO: f32 = 16.0 / 9.0;
-    const width: usize = 500;
-    let height: usize = (width as f32 / ASPECT_RATIO).floor() as usize;
-    let scale = 2;
+/// A CPU path tracer.
+#[derive(StructOpt)]
+#[structopt(name = "rray")]
+struct Opt {
+    #[structopt(short, long, default_value = "500")]
+    width: usize,
+    /// Defaults to a 16:9 image, or 2:1 when --projection is equirectangular.
+    /// A scene file that picks that projection itself still gets 16:9.
+    #[structopt(long)]
+    height: Option<usize>,
+    /// Samples per pixel, or the most any pixel gets with --adaptive.
+    #[structopt(short, long, default_value = "256")]
+    samples: u32,
+    /// Stops sampling a pixel once the standard error of its brightness is
+    /// under this fraction of it, so smooth areas finish early.
+    #[structopt(long)]
+    adaptive: Option<f32>,
+    /// The fewest samples a pixel gets with --adaptive.
+    #[structopt(long, default_value = "16")]
+    min_samples: u32,
+    /// Bounces before paths start being ended at random.
+    #[structopt(long, default_value = "3")]
+    min_depth: u32,
+    /// Paths stop here regardless.
+    #[structopt(long, default_value = "64")]
+    max_depth: u32,
+    /// Zero uses one thread per core.
+    #[structopt(short = "j", long, default_value = "0")]
+    threads: usize,
+    /// Random unless given, and logged so the render can be reproduced.
+    #[structopt(long)]
+    seed: Option<u64>,
+    /// Where sample values come from: independent, stratified, halton, sobol
+    /// or blue_noise.
+    #[structopt(long, default_value = "sobol")]
+    sampler: SamplerKind,
+    /// How samples are spread over the pixels around them: box, tent,
+    /// gaussian, mitchell or lanczos.
+    #[structopt(long, default_value = "box")]
+    filter: FilterKind,
+    /// How far samples reach, in pixels. Each filter has its own default.
+    #[structopt(long)]
+    filter_radius: Option<f32>,
+    /// Pixels are rendered in square tiles this many across.
+    #[structopt(long, default_value = "32")]
+    tile_size: usize,
+    /// The order tiles are started in: scanline, spiral or hilbert.
+    #[structopt(long, default_value = "spiral")]
+    tile_order: TileOrder,
+    /// A scene file, or an OBJ file, to render in place of the built in scene.
+    #[structopt(long, parse(from_os_str))]
+    scene: Option<PathBuf>,
+    /// Perspective, orthographic, fisheye or equirectangular, in place of the
+    /// scene's own.
+    #[structopt(long)]
+    projection: Option<Projection>,
+    /// Brightens (or, if negative, darkens) the image by this many stops.
+    #[structopt(long, default_value = "0")]
+    exposure: f32,
+    /// How bright values are brought into range: clamp, reinhard or aces.
+    #[structopt(long, default_value = "aces")]
+    tonemap: Operator,
+    /// Writes a .png, .ppm, .exr or .pfm instead of opening a window. EXR
+    /// and PFM files hold linear radiance, untouched by tone mapping.
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Half or float channels in EXR files.
+    #[structopt(long, default_value = "half")]
+    exr_precision: Precision,
+    /// Also renders a pass of what the camera sees first: albedo, normal,
+    /// position, depth, material_id or object_id, or of how many samples
+    /// each pixel took. Can be given more than once, and needs --output.
+    #[structopt(long = "aov", number_of_values = 1)]
+    aovs: Vec<Aov>,
+    /// Stops refining the preview window after this many seconds, even if
+    /// it hasn't reached --samples.
+    #[structopt(long)]
+    time_limit: Option<f32>,
+}
 
-    let camera = Camera::new(vec3(-2.0, 2.0, 1.0), vec3(0.0,0.0, -1.0), vec3(0.0, 1.0, 0.0), 20.0, ASPECT_RATIO);
+fn default_scene(aspect_ratio: f32, projection: Projection) -> Scene {
+    let camera = projection.camera(vec3(-2.0, 2.0, 1.0), vec3(0.0,0.0, -1.0), vec3(0.0, 1.0, 0.0), 20.0, aspect_ratio);
 
-    let mat_ground = Arc::new(Lambertian { albedo: vec3(0.8, 0.8, 0.0)});
-    let mat_centre = Arc::new(Lambertian { albedo: vec3(0.1, 0.2, 0.5)});
+    let mat_ground = Arc::new(Lambertian { albedo: Arc::new(SolidColour { colour: vec3(0.8, 0.8, 0.0) })});
+    let mat_centre = Arc::new(Lambertian { albedo: Arc::new(SolidColour { colour: vec3(0.1, 0.2, 0.5) })});
     let mat_left = Arc::new( Dielectric { refractive_index: 1.5 });
-    let mat_right = Arc::new( Metal { albedo: vec3(0.8, 0.6, 0.2), fuzz: 0.0});
+    let mat_right = Arc::new( Metal { albedo: Arc::new(SolidColour { colour: vec3(0.8, 0.6, 0.2) }), fuzz: 0.0});
 
     let world = HitableList {
         list: vec![
@@ -51,13 +125,142 @@ fn main() -> Result<()> {
             Box::new(Sphere { centre: vec3(1.0, 0.0, -1.0), radius: 0.5, material: mat_right}),
         ],
     };
+    Scene {
+        camera,
+        world,
+        background: Background::Sky,
+    }
+}
+
+// Frames the whole model, looking down -z.
+fn obj_scene(path: &Path, aspect_ratio: f32, projection: Projection) -> Result<Scene> {
+    let world = obj::load(path)?;
+    let (centre, radius) = match world.bounding_box(0.0, 0.0) {
+        Some(b) => (b.centroid(), 0.5 * b.extent().norm()),
+        None => (vec3(0.0, 0.0, 0.0), 1.0),
+    };
+    let from = centre + vec3(0.0, 0.0, 3.0 * radius);
+    let camera = projection.camera(from, centre, vec3(0.0, 1.0, 0.0), 40.0, aspect_ratio);
+    Ok(Scene {
+        camera,
+        world,
+        background: Background::Sky,
+    })
+}
+
+fn main() -> Result<()> {
+    if env::var("RUST_LOG").is_err() {
+        env::set_var("RUST_LOG", "trace")
+    }
+    pretty_env_logger::init();
+    let opt = Opt::from_args();
+
+    let width = opt.width;
+    // Panoramas cover twice as many degrees across as they do up.
+    let default_aspect = if opt.projection == Some(Projection::Equirectangular) { 2.0 } else { 16.0 / 9.0 };
+    let height = opt.height.unwrap_or(((width as f32 / default_aspect).floor() as usize).max(1));
+    if width == 0 || height == 0 {
+        bail!("the image must be at least a pixel across and a pixel high");
+    }
+    if opt.samples == 0 {
+        bail!("--samples must be positive");
+    }
+    if let Some(threshold) = opt.adaptive {
+        if threshold.is_nan() || threshold <= 0.0 {
+            bail!("--adaptive must be positive");
+        }
+        if opt.min_samples > opt.samples {
+            bail!("--min-samples can't be more than --samples");
+        }
+    }
+    if opt.min_depth > opt.max_depth {
+        bail!("--min-depth can't be more than --max-depth");
+    }
+    let filter = Filter {
+        kind: opt.filter,
+        radius: opt.filter_radius.unwrap_or_else(|| opt.filter.default_radius()),
+    };
+    if !filter.radius.is_finite() || filter.radius <= 0.0 {
+        bail!("--filter-radius must be positive and finite");
+    }
+    if opt.tile_size == 0 {
+        bail!("--tile-size must be positive");
+    }
+    let aspect_ratio = width as f32 / height as f32;
+    let scale = 2;
+
+    let scene = match &opt.scene {
+        Some(path) if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("obj")) => {
+            obj_scene(path, aspect_ratio, opt.projection.unwrap_or(Projection::Perspective))?
+        }
+        Some(path) => scene::load(path, aspect_ratio, opt.projection)?,
+        None => default_scene(aspect_ratio, opt.projection.unwrap_or(Projection::Perspective)),
+    };
+
+    let seed = opt.seed.unwrap_or_else(|| thread_rng().gen());
+    info!("Rendering {}x{} at {} samples with seed {}", width, height, opt.samples, seed);
+    let settings = RenderSettings {
+        width,
+        height,
+        samples: opt.samples,
+        min_samples: opt.min_samples,
+        threshold: opt.adaptive,
+        min_depth: opt.min_depth,
+        max_depth: opt.max_depth,
+        threads: opt.threads,
+        seed,
+        sampler: opt.sampler,
+        filter,
+        tile_size: opt.tile_size,
+        tile_order: opt.tile_order,
+        background: scene.background,
+        aovs: opt.aovs,
+    };
 
+    let tone_mapper = ToneMapper {
+        exposure: opt.exposure,
+        operator: opt.tonemap,
+    };
     let start = Instant::now();
-    let buf = renderer::render(width, height, camera, world);
-    info!("Took {:?} to render", start.elapsed());
+    let (open, close) = scene.camera.shutter();
+    let world = scene.world.into_bvh(open, close);
 
+    if let Some(path) = opt.output {
+        let frame = renderer::render(&settings, scene.camera.as_ref(), world, |_, _| true)?;
+        info!("Took {:?} to render", start.elapsed());
+        for written in output::write_frame(&path, &frame, &tone_mapper, opt.exr_precision)? {
+            info!("Wrote {}", written.display());
+        }
+        return Ok(());
+    }
+    if !settings.aovs.is_empty() {
+        warn!("AOVs are only kept with --output");
+    }
+
+    // The window shows the image converging a sample at a time, painted in
+    // tile by tile, until it has them all, runs out of time or is closed.
     let mut window = Window::new("rray", width * scale, height * scale, WindowOptions::default())?;
-    window.limit_update_rate(Some(Duration::from_micros(16600)));
+    let frame_time = Duration::from_micros(16600);
+    window.limit_update_rate(Some(frame_time));
+    let time_limit = opt.time_limit.map(Duration::from_secs_f32);
+    let mut buf = vec![0; width * height];
+    let mut last_update = Instant::now();
+    let mut shown = Ok(());
+    let frame = renderer::render_progressive(&settings, scene.camera.as_ref(), world, |tile, values| {
+        for (k, c) in tile.positions(width).zip(values) {
+            buf[k] = tone_mapper.display(c);
+        }
+        // Tiles can finish far quicker than the window refreshes.
+        if last_update.elapsed() >= frame_time {
+            shown = window.update_with_buffer(&buf, width, height);
+            last_update = Instant::now();
+        }
+        shown.is_ok() && window.is_open() && time_limit.is_none_or(|limit| start.elapsed() < limit)
+    })?;
+    shown?;
+    info!("Took {:?} to render", start.elapsed());
+
+    let buf = tone_mapper.to_bgra(&frame.beauty);
     while window.is_open() {
         window.update_with_buffer(&buf, width, height)?;
     }
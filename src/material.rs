@@ -1,54 +1,155 @@
 use crate::hitable::HitRecord;
 use crate::ray::Ray;
 use crate::renderer::*; //TODO: Move?
+use crate::sampler::Sampler;
+use crate::texture::Texture;
 use glm::{vec3, Vec3};
-use rand::prelude::*;
+use std::f32::consts::FRAC_1_PI;
+use std::sync::Arc;
 
 pub struct Scatter {
     pub ray: Ray,
     pub attenuation: Vec3,
+    // Density the direction was sampled with, or None for specular bounces
+    // which can't be reached by sampling lights.
+    pub pdf: Option<f32>,
 }
 
 pub trait Material {
-    fn scatter(self: &Self, ray: &Ray, hit_record: &HitRecord, rng: &mut ThreadRng) -> Scatter;
+    // None means the ray was absorbed.
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scatter>;
+
+    fn emitted(&self, _hit_record: &HitRecord) -> Vec3 {
+        vec3(0.0, 0.0, 0.0)
+    }
+
+    fn is_emissive(&self) -> bool {
+        false
+    }
+
+    // BSDF times the cosine term for light arriving from `direction`, and the
+    // density `scatter` would have picked it with. Only needed by materials
+    // that return a pdf from `scatter`.
+    fn eval(&self, _ray: &Ray, _hit_record: &HitRecord, _direction: &Vec3) -> Vec3 {
+        vec3(0.0, 0.0, 0.0)
+    }
+
+    fn pdf(&self, _ray: &Ray, _hit_record: &HitRecord, _direction: &Vec3) -> f32 {
+        0.0
+    }
+
+    // Base colour for the albedo pass.
+    fn albedo(&self, _hit_record: &HitRecord) -> Vec3 {
+        vec3(0.0, 0.0, 0.0)
+    }
+
+    // Identifies the material in the material ID pass, zero if it has none.
+    fn id(&self) -> u32 {
+        0
+    }
+}
+
+// Gives another material an ID, leaving everything else about it alone.
+pub struct TaggedMaterial {
+    pub material: Arc<dyn Material + Sync + Send>,
+    pub id: u32,
+}
+
+impl Material for TaggedMaterial {
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scatter> {
+        self.material.scatter(ray, hit_record, sampler)
+    }
+
+    fn emitted(&self, hit_record: &HitRecord) -> Vec3 {
+        self.material.emitted(hit_record)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+
+    fn eval(&self, ray: &Ray, hit_record: &HitRecord, direction: &Vec3) -> Vec3 {
+        self.material.eval(ray, hit_record, direction)
+    }
+
+    fn pdf(&self, ray: &Ray, hit_record: &HitRecord, direction: &Vec3) -> f32 {
+        self.material.pdf(ray, hit_record, direction)
+    }
+
+    fn albedo(&self, hit_record: &HitRecord) -> Vec3 {
+        self.material.albedo(hit_record)
+    }
+
+    fn id(&self) -> u32 {
+        self.id
+    }
 }
 
 // Diffuse
 pub struct Lambertian {
-    pub albedo: Vec3,
+    pub albedo: Arc<dyn Texture + Sync + Send>,
 }
 
 impl Material for Lambertian {
-    fn scatter(self: &Self, _: &Ray, hit_record: &HitRecord, rng: &mut ThreadRng) -> Scatter {
-        let target = hit_record.position + hit_record.normal + random_in_unit_sphere(rng);
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scatter> {
+        // Offsetting the normal by a point on the unit sphere gives a cosine
+        // distributed direction.
+        let mut direction = hit_record.normal + random_unit_vector(sampler);
+        if glm::length2(&direction) < 1e-8 {
+            direction = hit_record.normal;
+        }
+        let cosine = glm::dot(&direction.normalize(), &hit_record.normal).max(0.0);
         let scattered = Ray {
             origin: hit_record.position,
-            direction: target - hit_record.position,
+            direction,
+            time: ray.time,
+            free_flight: 0.0,
         };
-        Scatter {
+        Some(Scatter {
             ray: scattered,
-            attenuation: self.albedo,
-        }
+            attenuation: self.albedo.value(&hit_record.uv, &hit_record.position),
+            pdf: Some(cosine * FRAC_1_PI),
+        })
+    }
+
+    fn eval(&self, _: &Ray, hit_record: &HitRecord, direction: &Vec3) -> Vec3 {
+        let cosine = glm::dot(&direction.normalize(), &hit_record.normal).max(0.0);
+        self.albedo.value(&hit_record.uv, &hit_record.position) * (cosine * FRAC_1_PI)
+    }
+
+    fn pdf(&self, _: &Ray, hit_record: &HitRecord, direction: &Vec3) -> f32 {
+        glm::dot(&direction.normalize(), &hit_record.normal).max(0.0) * FRAC_1_PI
+    }
+
+    fn albedo(&self, hit_record: &HitRecord) -> Vec3 {
+        self.albedo.value(&hit_record.uv, &hit_record.position)
     }
 }
 
 pub struct Metal {
-    pub albedo: Vec3,
+    pub albedo: Arc<dyn Texture + Sync + Send>,
     pub fuzz: f32,
 }
 
 impl Material for Metal {
-    fn scatter(self: &Self, ray: &Ray, hit_record: &HitRecord, rng: &mut ThreadRng) -> Scatter {
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scatter> {
         let reflected = reflect(ray.direction, hit_record.normal);
-        let attenuation = self.albedo;
+        let attenuation = self.albedo.value(&hit_record.uv, &hit_record.position);
         let scattered = Ray {
             origin: hit_record.position,
-            direction: reflected + self.fuzz * random_in_unit_sphere(rng),
+            direction: reflected + self.fuzz * random_in_unit_sphere(sampler),
+            time: ray.time,
+            free_flight: 0.0,
         };
-        Scatter {
+        Some(Scatter {
             ray: scattered,
             attenuation,
-        }
+            pdf: None,
+        })
+    }
+
+    fn albedo(&self, hit_record: &HitRecord) -> Vec3 {
+        self.albedo.value(&hit_record.uv, &hit_record.position)
     }
 }
 
@@ -73,7 +174,7 @@ pub struct Dielectric {
 }
 
 impl Material for Dielectric {
-    fn scatter(self: &Self, ray: &Ray, hit_record: &HitRecord, rng: &mut ThreadRng) -> Scatter {
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scatter> {
         let attenuation = vec3(1.0, 1.0, 1.0);
         let refraction_ratio = if hit_record.front_face {
             1.0 / self.refractive_index
@@ -85,17 +186,84 @@ impl Material for Dielectric {
         let cos_theta = f32::min(glm::dot(&unit_direction, &hit_record.normal), 1.0); // This is supposed to be negative unit_direction...
         let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
         let cannot_refract = refraction_ratio * sin_theta > 1.0;
-        let direction = if cannot_refract || reflectance(cos_theta, refraction_ratio) > rng.gen::<f32>() {
+        let direction = if cannot_refract || reflectance(cos_theta, refraction_ratio) > sampler.next_1d() {
             reflect(unit_direction, hit_record.normal)
         } else {
             refract(unit_direction, hit_record.normal, refraction_ratio)
         };
-        Scatter {
+        Some(Scatter {
             ray: Ray {
                 origin: hit_record.position,
                 direction,
+                time: ray.time,
+                free_flight: 0.0,
             },
             attenuation,
+            pdf: None,
+        })
+    }
+
+    // Clear glass passes everything through, so it's white.
+    fn albedo(&self, _: &HitRecord) -> Vec3 {
+        vec3(1.0, 1.0, 1.0)
+    }
+}
+
+// Emits light from its front face and absorbs everything that hits it.
+pub struct DiffuseLight {
+    pub emit: Vec3,
+}
+
+impl Material for DiffuseLight {
+    fn scatter(&self, _: &Ray, _: &HitRecord, _: &mut dyn Sampler) -> Option<Scatter> {
+        None
+    }
+
+    fn is_emissive(&self) -> bool {
+        true
+    }
+
+    fn emitted(&self, hit_record: &HitRecord) -> Vec3 {
+        if hit_record.front_face {
+            self.emit
+        } else {
+            vec3(0.0, 0.0, 0.0)
         }
     }
 }
+
+// Phase function for participating media, scattering equally in every
+// direction.
+pub struct Isotropic {
+    pub albedo: Arc<dyn Texture + Sync + Send>,
+}
+
+const INV_4PI: f32 = 0.25 * FRAC_1_PI;
+
+impl Material for Isotropic {
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scatter> {
+        Some(Scatter {
+            ray: Ray {
+                origin: hit_record.position,
+                direction: random_unit_vector(sampler),
+                time: ray.time,
+                free_flight: 0.0,
+            },
+            attenuation: self.albedo.value(&hit_record.uv, &hit_record.position),
+            pdf: Some(INV_4PI),
+        })
+    }
+
+    // There's no surface, so no cosine term.
+    fn eval(&self, _: &Ray, hit_record: &HitRecord, _: &Vec3) -> Vec3 {
+        self.albedo.value(&hit_record.uv, &hit_record.position) * INV_4PI
+    }
+
+    fn pdf(&self, _: &Ray, _: &HitRecord, _: &Vec3) -> f32 {
+        INV_4PI
+    }
+
+    fn albedo(&self, hit_record: &HitRecord) -> Vec3 {
+        self.albedo.value(&hit_record.uv, &hit_record.position)
+    }
+}
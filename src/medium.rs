@@ -0,0 +1,102 @@
+use crate::aabb::Aabb;
+use crate::hitable::{HitRecord, Hitable};
+use crate::material::Material;
+use crate::noise::{hash, to_unit};
+use crate::ray::Ray;
+use glm::vec3;
+use std::sync::Arc;
+
+// A volume of uniform density filling a closed boundary, like smoke or fog.
+// Rays passing through scatter at an exponentially distributed distance, off
+// `phase`, which is normally `Isotropic`. The distance comes from the ray's
+// `free_flight` sample, mixed with where the ray enters and leaves this
+// medium so that each one it crosses gets a value of its own.
+pub struct ConstantMedium {
+    pub boundary: Box<dyn Hitable + Sync + Send>,
+    pub density: f32,
+    pub phase: Arc<dyn Material + Sync + Send>,
+}
+
+impl Hitable for ConstantMedium {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        // Where the ray enters and leaves the boundary, even if it starts
+        // inside.
+        let enter = self.boundary.hit(ray, f32::MIN, f32::MAX)?;
+        let exit = self.boundary.hit(ray, enter.time + 0.0001, f32::MAX)?;
+        let t0 = enter.time.max(t_min).max(0.0);
+        let t1 = exit.time.min(t_max);
+        if t0 >= t1 {
+            return None;
+        }
+
+        let length = glm::length(&ray.direction);
+        // Sharing one value would mean a ray that gets through one medium
+        // always gets through the next.
+        let bits = |x: f32| x.to_bits() as u64;
+        let u = to_unit(hash(bits(ray.free_flight) ^ hash(bits(enter.time) ^ hash(bits(exit.time)))));
+        let distance = -(1.0 - u).ln() / self.density;
+        if distance > (t1 - t0) * length {
+            return None;
+        }
+        let t = t0 + distance / length;
+        // Normals mean nothing inside a volume.
+        Some(HitRecord::new(t, ray.at(t), vec3(1.0, 0.0, 0.0), self.phase.clone()))
+    }
+
+    fn bounding_box(&self, time0: f32, time1: f32) -> Option<Aabb> {
+        self.boundary.bounding_box(time0, time1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hitable::HitableList;
+    use crate::material::{Isotropic, Lambertian};
+    use crate::sphere::Sphere;
+    use crate::texture::SolidColour;
+
+    fn fog(z: f32) -> Box<dyn Hitable + Sync + Send> {
+        let white = || Arc::new(SolidColour { colour: vec3(1.0, 1.0, 1.0) });
+        Box::new(ConstantMedium {
+            boundary: Box::new(Sphere {
+                centre: vec3(0.0, 0.0, z),
+                radius: 1.0,
+                material: Arc::new(Lambertian { albedo: white() }),
+            }),
+            density: 0.5,
+            phase: Arc::new(Isotropic { albedo: white() }),
+        })
+    }
+
+    // The fraction of rays down the z axis, with free-flight samples spread
+    // evenly over [0, 1), that make it through.
+    fn transmittance(world: &dyn Hitable) -> f32 {
+        let n = 10_000;
+        let through = (0..n)
+            .filter(|&i| {
+                let ray = Ray {
+                    origin: vec3(0.0, 0.0, 5.0),
+                    direction: vec3(0.0, 0.0, -1.0),
+                    time: 0.0,
+                    free_flight: (i as f32 + 0.5) / n as f32,
+                };
+                world.hit(&ray, 0.001, f32::MAX).is_none()
+            })
+            .count();
+        through as f32 / n as f32
+    }
+
+    // Each sphere is two units through at a density of a half.
+    #[test]
+    fn one_medium() {
+        let t = transmittance(&HitableList { list: vec![fog(0.0)] });
+        assert!((t - (-1.0f32).exp()).abs() < 0.015, "{}", t);
+    }
+
+    #[test]
+    fn media_in_series_multiply() {
+        let t = transmittance(&HitableList { list: vec![fog(0.0), fog(-3.0), fog(-6.0)] });
+        assert!((t - (-3.0f32).exp()).abs() < 0.015, "{}", t);
+    }
+}
@@ -0,0 +1,232 @@
+use crate::aabb::Aabb;
+use crate::bvh::Bvh;
+use crate::hitable::{HitRecord, Hitable};
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::triangle;
+use glm::{vec2, Vec2, Vec3};
+use std::sync::Arc;
+
+// Indexed triangle mesh. `normals` and `uvs` are either empty or hold one
+// entry per position, and every face shares the one material.
+pub struct Mesh {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<Vec2>,
+    pub indices: Vec<[u32; 3]>,
+    pub material: Arc<dyn Material + Sync + Send>,
+    // The area of each face and all those before it, for picking faces to
+    // sample as a light.
+    cumulative_areas: Vec<f32>,
+}
+
+impl Mesh {
+    pub fn new(
+        positions: Vec<Vec3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<Vec2>,
+        indices: Vec<[u32; 3]>,
+        material: Arc<dyn Material + Sync + Send>,
+    ) -> Self {
+        let mut mesh = Self {
+            positions,
+            normals,
+            uvs,
+            indices,
+            material,
+            cumulative_areas: Vec::new(),
+        };
+        let mut total = 0.0;
+        mesh.cumulative_areas = (0..mesh.num_faces())
+            .map(|face| {
+                total += mesh.face_area(face);
+                total
+            })
+            .collect();
+        mesh
+    }
+
+    pub fn total_area(&self) -> f32 {
+        self.cumulative_areas.last().copied().unwrap_or(0.0)
+    }
+
+    // A parallelogram with one corner at `q` spanned by the edges `u` and
+    // `v`, facing along u x v.
+    pub fn quad(q: Vec3, u: Vec3, v: Vec3, material: Arc<dyn Material + Sync + Send>) -> Self {
+        Self::new(
+            vec![q, q + u, q + u + v, q + v],
+            Vec::new(),
+            vec![vec2(0.0, 0.0), vec2(1.0, 0.0), vec2(1.0, 1.0), vec2(0.0, 1.0)],
+            vec![[0, 1, 2], [0, 2, 3]],
+            material,
+        )
+    }
+
+    pub fn num_faces(&self) -> usize {
+        self.indices.len()
+    }
+
+    // Splits the mesh into one primitive per face and builds a hierarchy over
+    // them, so large meshes don't get tested face by face.
+    pub fn into_bvh(self) -> Bvh<MeshFace> {
+        let mesh = Arc::new(self);
+        let faces = (0..mesh.num_faces())
+            .map(|face| MeshFace {
+                mesh: mesh.clone(),
+                face,
+            })
+            .collect();
+        // Faces don't move, so any time will do.
+        Bvh::new(faces, 0.0, 0.0)
+    }
+
+    pub fn face_positions(&self, face: usize) -> [&Vec3; 3] {
+        let [i0, i1, i2] = self.indices[face];
+        [
+            &self.positions[i0 as usize],
+            &self.positions[i1 as usize],
+            &self.positions[i2 as usize],
+        ]
+    }
+
+    pub fn face_bounding_box(&self, face: usize) -> Aabb {
+        Aabb::from_points(self.face_positions(face).iter().copied())
+    }
+
+    pub fn face_area(&self, face: usize) -> f32 {
+        let [p0, p1, p2] = self.face_positions(face);
+        triangle::area(p0, p1, p2)
+    }
+
+    // Density of reaching the face along `direction` when sampling its area
+    // uniformly, which the whole mesh scales by the face's share of the area.
+    fn face_pdf(&self, face: usize, origin: &Vec3, direction: &Vec3, area: f32) -> f32 {
+        let [p0, p1, p2] = self.face_positions(face);
+        let ray = Ray {
+            origin: *origin,
+            direction: *direction,
+            time: 0.0,
+            free_flight: 0.0,
+        };
+        match triangle::intersect(&ray, p0, p1, p2, 0.001, f32::MAX) {
+            Some((t, _, _)) => {
+                let normal = glm::cross(&(p1 - p0), &(p2 - p0)).normalize();
+                triangle::solid_angle_pdf(&ray, t, &normal, area)
+            }
+            None => 0.0,
+        }
+    }
+
+    fn face_sample_direction(&self, face: usize, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let [p0, p1, p2] = self.face_positions(face);
+        triangle::sample_point(p0, p1, p2, sampler) - origin
+    }
+
+    pub fn face_hit(&self, face: usize, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let [i0, i1, i2] = self.indices[face];
+        let (i0, i1, i2) = (i0 as usize, i1 as usize, i2 as usize);
+        let (p0, p1, p2) = (&self.positions[i0], &self.positions[i1], &self.positions[i2]);
+        let (t, b1, b2) = triangle::intersect(ray, p0, p1, p2, t_min, t_max)?;
+        let b0 = 1.0 - b1 - b2;
+
+        let outward_normal = glm::cross(&(p1 - p0), &(p2 - p0)).normalize();
+        let mut rec = HitRecord::new(t, ray.at(t), outward_normal, self.material.clone());
+        rec.set_face_normal(ray, &outward_normal);
+
+        if !self.normals.is_empty() {
+            let shading = (b0 * self.normals[i0] + b1 * self.normals[i1] + b2 * self.normals[i2]).normalize();
+            // Keep the shading normal on the same side as the geometric one.
+            rec.normal = if rec.front_face { shading } else { -shading };
+        }
+        rec.uv = if self.uvs.is_empty() {
+            vec2(b1, b2)
+        } else {
+            b0 * self.uvs[i0] + b1 * self.uvs[i1] + b2 * self.uvs[i2]
+        };
+        Some(rec)
+    }
+}
+
+impl Hitable for Mesh {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let mut closest_so_far = t_max;
+        let mut temp_rec = None;
+        for face in 0..self.num_faces() {
+            if let Some(rec) = self.face_hit(face, ray, t_min, closest_so_far) {
+                closest_so_far = rec.time;
+                temp_rec = Some(rec);
+            }
+        }
+        temp_rec
+    }
+
+    fn bounding_box(&self, _: f32, _: f32) -> Option<Aabb> {
+        Some(Aabb::from_points(self.positions.iter()))
+    }
+
+    fn lights<'a>(&'a self, lights: &mut Vec<&'a (dyn Hitable + Sync)>) {
+        if self.material.is_emissive() {
+            lights.push(self);
+        }
+    }
+
+    // Samples faces in proportion to their area. Finding the face a direction
+    // reaches still means testing every one, so meshes big enough for that
+    // to be slow should be split with `into_bvh` instead.
+    fn pdf(&self, origin: &Vec3, direction: &Vec3) -> f32 {
+        let ray = Ray {
+            origin: *origin,
+            direction: *direction,
+            time: 0.0,
+            free_flight: 0.0,
+        };
+        let mut closest = None;
+        let mut closest_so_far = f32::MAX;
+        for face in 0..self.num_faces() {
+            if let Some(rec) = self.face_hit(face, &ray, 0.001, closest_so_far) {
+                closest_so_far = rec.time;
+                closest = Some(face);
+            }
+        }
+        match closest {
+            Some(face) => self.face_pdf(face, origin, direction, self.total_area()),
+            None => 0.0,
+        }
+    }
+
+    fn sample_direction(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let target = sampler.next_1d() * self.total_area();
+        let chosen = self.cumulative_areas.partition_point(|&area| area < target);
+        self.face_sample_direction(chosen.min(self.num_faces() - 1), origin, sampler)
+    }
+}
+
+pub struct MeshFace {
+    pub mesh: Arc<Mesh>,
+    pub face: usize,
+}
+
+impl Hitable for MeshFace {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        self.mesh.face_hit(self.face, ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self, _: f32, _: f32) -> Option<Aabb> {
+        Some(self.mesh.face_bounding_box(self.face))
+    }
+
+    fn lights<'a>(&'a self, lights: &mut Vec<&'a (dyn Hitable + Sync)>) {
+        if self.mesh.material.is_emissive() {
+            lights.push(self);
+        }
+    }
+
+    fn pdf(&self, origin: &Vec3, direction: &Vec3) -> f32 {
+        self.mesh.face_pdf(self.face, origin, direction, self.mesh.face_area(self.face))
+    }
+
+    fn sample_direction(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        self.mesh.face_sample_direction(self.face, origin, sampler)
+    }
+}
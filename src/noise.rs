@@ -0,0 +1,207 @@
+use crate::texture::Texture;
+use glm::{vec3, Vec2, Vec3};
+use std::sync::Arc;
+
+const POINT_COUNT: usize = 256;
+
+// Gradient noise over a lattice of random unit vectors. The tables are
+// generated from the seed up front, so lookups are pure functions of the
+// position and a render is the same on any thread.
+pub struct Perlin {
+    gradients: Vec<Vec3>,
+    perm_x: Vec<usize>,
+    perm_y: Vec<usize>,
+    perm_z: Vec<usize>,
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Self {
+        // Numbered draws through `hash`, rather than a library generator
+        // whose output could change between versions.
+        let mut draws = 0u64;
+        let mut next = || {
+            draws += 1;
+            to_unit(hash(seed ^ hash(draws)))
+        };
+        let gradients = (0..POINT_COUNT)
+            .map(|_| {
+                loop {
+                    let v = vec3(2.0 * next() - 1.0, 2.0 * next() - 1.0, 2.0 * next() - 1.0);
+                    let length2 = glm::length2(&v);
+                    if length2 > 1e-4 && length2 <= 1.0 {
+                        return v.normalize();
+                    }
+                }
+            })
+            .collect();
+        // Fisher-Yates.
+        let mut permutation = || {
+            let mut p: Vec<usize> = (0..POINT_COUNT).collect();
+            for i in (1..POINT_COUNT).rev() {
+                let j = ((next() * (i + 1) as f32) as usize).min(i);
+                p.swap(i, j);
+            }
+            p
+        };
+        Self {
+            gradients,
+            perm_x: permutation(),
+            perm_y: permutation(),
+            perm_z: permutation(),
+        }
+    }
+
+    // Roughly in [-1, 1].
+    pub fn noise(&self, p: &Vec3) -> f32 {
+        let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let (u, v, w) = (p.x - fx, p.y - fy, p.z - fz);
+        let (i, j, k) = (fx as i64, fy as i64, fz as i64);
+
+        // Hermite smoothing hides the lattice.
+        let (uu, vv, ww) = (u * u * (3.0 - 2.0 * u), v * v * (3.0 - 2.0 * v), w * w * (3.0 - 2.0 * w));
+        let mask = (POINT_COUNT - 1) as i64;
+        let mut accum = 0.0;
+        for di in 0..2 {
+            for dj in 0..2 {
+                for dk in 0..2 {
+                    let index = self.perm_x[((i + di) & mask) as usize]
+                        ^ self.perm_y[((j + dj) & mask) as usize]
+                        ^ self.perm_z[((k + dk) & mask) as usize];
+                    let (a, b, c) = (di as f32, dj as f32, dk as f32);
+                    let weight = vec3(u - a, v - b, w - c);
+                    accum += (a * uu + (1.0 - a) * (1.0 - uu))
+                        * (b * vv + (1.0 - b) * (1.0 - vv))
+                        * (c * ww + (1.0 - c) * (1.0 - ww))
+                        * glm::dot(&self.gradients[index], &weight);
+                }
+            }
+        }
+        accum
+    }
+
+    // Sum of `octaves` layers of noise, each at twice the frequency and half
+    // the weight of the last.
+    pub fn turbulence(&self, p: &Vec3, octaves: u32) -> f32 {
+        let mut accum = 0.0;
+        let mut p = *p;
+        let mut weight = 1.0;
+        for _ in 0..octaves {
+            accum += weight * self.noise(&p);
+            weight *= 0.5;
+            p *= 2.0;
+        }
+        accum.abs()
+    }
+}
+
+fn mix(a: &Vec3, b: &Vec3, t: f32) -> Vec3 {
+    (1.0 - t) * a + t * b
+}
+
+// Plain noise when `octaves` is one, turbulence above that.
+pub struct NoiseTexture {
+    pub noise: Perlin,
+    pub colour: Arc<dyn Texture + Sync + Send>,
+    pub scale: f32,
+    pub octaves: u32,
+}
+
+impl Texture for NoiseTexture {
+    fn value(&self, uv: &Vec2, position: &Vec3) -> Vec3 {
+        let p = self.scale * position;
+        let n = if self.octaves <= 1 {
+            0.5 * (1.0 + self.noise.noise(&p))
+        } else {
+            self.noise.turbulence(&p, self.octaves)
+        };
+        n.clamp(0.0, 1.0) * self.colour.value(uv, position)
+    }
+}
+
+// Veins running along z, 2π / `scale` apart and pushed around by turbulence.
+pub struct Marble {
+    pub noise: Perlin,
+    pub low: Arc<dyn Texture + Sync + Send>,
+    pub high: Arc<dyn Texture + Sync + Send>,
+    pub scale: f32,
+    pub turbulence: f32,
+    pub octaves: u32,
+}
+
+impl Texture for Marble {
+    fn value(&self, uv: &Vec2, position: &Vec3) -> Vec3 {
+        let phase = self.scale * position.z + self.turbulence * self.noise.turbulence(position, self.octaves);
+        let t = 0.5 * (1.0 + phase.sin());
+        mix(&self.low.value(uv, position), &self.high.value(uv, position), t)
+    }
+}
+
+// Growth rings around the y axis, `scale` to a unit, made irregular by
+// turbulence.
+pub struct Wood {
+    pub noise: Perlin,
+    pub low: Arc<dyn Texture + Sync + Send>,
+    pub high: Arc<dyn Texture + Sync + Send>,
+    pub scale: f32,
+    pub turbulence: f32,
+    pub octaves: u32,
+}
+
+impl Texture for Wood {
+    fn value(&self, uv: &Vec2, position: &Vec3) -> Vec3 {
+        let radius = (position.x * position.x + position.z * position.z).sqrt();
+        let rings = self.scale * radius + self.turbulence * self.noise.turbulence(position, self.octaves);
+        let t = rings - rings.floor();
+        mix(&self.low.value(uv, position), &self.high.value(uv, position), t)
+    }
+}
+
+// SplitMix64 finaliser, for turning coordinates into well mixed bits.
+pub fn hash(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+// A hash as a float in [0, 1).
+pub fn to_unit(x: u64) -> f32 {
+    (x >> 40) as f32 / (1u64 << 24) as f32
+}
+
+// Cellular noise: the distance to the nearest of one random feature point per
+// unit cell, giving Voronoi cells that are `low` at their centres.
+pub struct Worley {
+    pub seed: u64,
+    pub low: Arc<dyn Texture + Sync + Send>,
+    pub high: Arc<dyn Texture + Sync + Send>,
+    pub scale: f32,
+}
+
+impl Worley {
+    fn feature_point(&self, cell: (i64, i64, i64)) -> Vec3 {
+        let h = hash(self.seed ^ hash(cell.0 as u64 ^ hash(cell.1 as u64 ^ hash(cell.2 as u64))));
+        let (a, b, c) = (hash(h), hash(h ^ 1), hash(h ^ 2));
+        vec3(cell.0 as f32 + to_unit(a), cell.1 as f32 + to_unit(b), cell.2 as f32 + to_unit(c))
+    }
+
+    pub fn distance(&self, p: &Vec3) -> f32 {
+        let (i, j, k) = (p.x.floor() as i64, p.y.floor() as i64, p.z.floor() as i64);
+        let mut nearest = f32::MAX;
+        for di in -1..=1 {
+            for dj in -1..=1 {
+                for dk in -1..=1 {
+                    let feature = self.feature_point((i + di, j + dj, k + dk));
+                    nearest = nearest.min(glm::length2(&(feature - p)));
+                }
+            }
+        }
+        nearest.sqrt()
+    }
+}
+
+impl Texture for Worley {
+    fn value(&self, uv: &Vec2, position: &Vec3) -> Vec3 {
+        let t = self.distance(&(self.scale * position)).min(1.0);
+        mix(&self.low.value(uv, position), &self.high.value(uv, position), t)
+    }
+}
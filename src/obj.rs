@@ -0,0 +1,447 @@
+use crate::aov;
+use crate::hitable::{Hitable, HitableList};
+use crate::material::{Dielectric, Lambertian, Material, Metal, TaggedMaterial};
+use crate::mesh::Mesh;
+use crate::texture::{ImageTexture, SolidColour, Texture, WrapMode};
+use anyhow::{anyhow, bail, Context, Result};
+use glm::{vec2, vec3, Vec2, Vec3};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Loads a Wavefront OBJ file, along with any MTL libraries it references, as
+// one mesh per group and material.
+pub fn load(path: &Path) -> Result<HitableList> {
+    let list = meshes(path)?
+        .into_iter()
+        .map(|mesh| -> Box<dyn Hitable + Sync + Send> { Box::new(mesh.into_bvh()) })
+        .collect();
+    Ok(HitableList { list })
+}
+
+fn meshes(path: &Path) -> Result<Vec<Mesh>> {
+    let source = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut positions = Vec::new();
+    let mut uvs = Vec::new();
+    let mut normals = Vec::new();
+    let mut materials = HashMap::new();
+    let mut builders: Vec<MeshBuilder> = Vec::new();
+    let mut group = String::new();
+    let mut material = String::new();
+
+    for (i, line) in source.lines().enumerate() {
+        let line_no = i + 1;
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(k) if !k.starts_with('#') => k,
+            _ => continue,
+        };
+        let args: Vec<&str> = tokens.collect();
+        let at = || format!("{}:{}", path.display(), line_no);
+
+        match keyword {
+            "v" => positions.push(parse_vec3(&args).with_context(at)?),
+            "vn" => normals.push(parse_vec3(&args).with_context(at)?),
+            "vt" => {
+                let u = parse_float(args.first()).with_context(at)?;
+                let v = args.get(1).map_or(Ok(0.0), |v| parse_float(Some(v))).with_context(at)?;
+                uvs.push(vec2(u, v));
+            }
+            "f" => {
+                if args.len() < 3 {
+                    bail!("{}: face needs at least three vertices", at());
+                }
+                let corners = args
+                    .iter()
+                    .map(|a| parse_corner(a, positions.len(), uvs.len(), normals.len()))
+                    .collect::<Result<Vec<_>>>()
+                    .with_context(at)?;
+                let builder = match builders.last_mut() {
+                    Some(b) if b.group == group && b.material == material => b,
+                    _ => {
+                        builders.push(MeshBuilder::new(&group, &material));
+                        builders.last_mut().unwrap()
+                    }
+                };
+                // Polygons are fanned around their first vertex.
+                for k in 1..corners.len() - 1 {
+                    builder.push_face([corners[0], corners[k], corners[k + 1]]);
+                }
+            }
+            "g" | "o" => group = args.join(" "),
+            "usemtl" => material = args.join(" "),
+            // A missing library only costs the look of the faces using it,
+            // so they're left to the default material.
+            "mtllib" => {
+                for lib in args {
+                    let lib = dir.join(lib);
+                    if lib.is_file() {
+                        materials.extend(load_mtl(&lib)?);
+                    } else {
+                        warn!("{}: can't find material library {}", at(), lib.display());
+                    }
+                }
+            }
+            // Smoothing groups, lines and points have no bearing on rendering.
+            _ => {}
+        }
+    }
+
+    let default_material: Arc<dyn Material + Sync + Send> = Arc::new(Lambertian {
+        albedo: Arc::new(SolidColour { colour: vec3(0.8, 0.8, 0.8) }),
+    });
+    let mut meshes = Vec::new();
+    for builder in builders {
+        let material = match materials.get(&builder.material) {
+            Some(m) => m.clone(),
+            None => {
+                if !builder.material.is_empty() {
+                    warn!("{}: unknown material '{}', using default", path.display(), builder.material);
+                }
+                default_material.clone()
+            }
+        };
+        meshes.push(builder.build(&positions, &uvs, &normals, material));
+    }
+    Ok(meshes)
+}
+
+// Maps MTL parameters onto the closest of our materials: anything with
+// transparency is glass, anything more specular than diffuse is metal.
+pub fn load_mtl(path: &Path) -> Result<HashMap<String, Arc<dyn Material + Sync + Send>>> {
+    let source = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut params: Vec<MtlParams> = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(k) if !k.starts_with('#') => k,
+            _ => continue,
+        };
+        let args: Vec<&str> = tokens.collect();
+        let at = || format!("{}:{}", path.display(), i + 1);
+
+        if keyword == "newmtl" {
+            params.push(MtlParams::new(args.join(" ")));
+            continue;
+        }
+        let current = match params.last_mut() {
+            Some(p) => p,
+            None => bail!("{}: '{}' before any newmtl", at(), keyword),
+        };
+        match keyword {
+            "Kd" => current.diffuse = parse_vec3(&args).with_context(at)?,
+            "Ks" => current.specular = parse_vec3(&args).with_context(at)?,
+            "Ns" => current.shininess = parse_float(args.first()).with_context(at)?,
+            "Ni" => current.refractive_index = parse_float(args.first()).with_context(at)?,
+            "d" => current.dissolve = parse_float(args.first()).with_context(at)?,
+            "Tr" => current.dissolve = 1.0 - parse_float(args.first()).with_context(at)?,
+            // Any options come before the file name.
+            "map_Kd" => current.diffuse_map = args.last().map(|f| dir.join(f)),
+            "map_Ks" => current.specular_map = args.last().map(|f| dir.join(f)),
+            _ => {}
+        }
+    }
+
+    let mut textures = HashMap::new();
+    let mut materials = HashMap::new();
+    for p in params.iter() {
+        let material = TaggedMaterial {
+            material: p.to_material(&mut textures)?,
+            id: aov::id_from_name(&p.name),
+        };
+        materials.insert(p.name.clone(), Arc::new(material) as Arc<dyn Material + Sync + Send>);
+    }
+    Ok(materials)
+}
+
+struct MtlParams {
+    name: String,
+    diffuse: Vec3,
+    specular: Vec3,
+    shininess: f32,
+    refractive_index: f32,
+    dissolve: f32,
+    diffuse_map: Option<PathBuf>,
+    specular_map: Option<PathBuf>,
+}
+
+impl MtlParams {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            diffuse: vec3(0.8, 0.8, 0.8),
+            specular: vec3(0.0, 0.0, 0.0),
+            shininess: 0.0,
+            refractive_index: 1.0,
+            dissolve: 1.0,
+            diffuse_map: None,
+            specular_map: None,
+        }
+    }
+
+    // Texture maps replace the colour they go with, and `textures` lets
+    // materials sharing an image share one copy of it.
+    fn to_material(
+        &self,
+        textures: &mut HashMap<PathBuf, Arc<ImageTexture>>,
+    ) -> Result<Arc<dyn Material + Sync + Send>> {
+        let mut texture = |colour: Vec3, map: &Option<PathBuf>| -> Result<Arc<dyn Texture + Sync + Send>> {
+            Ok(match map {
+                Some(path) => match textures.get(path) {
+                    Some(t) => t.clone(),
+                    None => {
+                        let t = Arc::new(ImageTexture::load(path, WrapMode::Repeat)?);
+                        textures.insert(path.clone(), t.clone());
+                        t
+                    }
+                },
+                None => Arc::new(SolidColour { colour }),
+            })
+        };
+        let material: Arc<dyn Material + Sync + Send> = if self.dissolve < 1.0 {
+            let refractive_index = if self.refractive_index > 1.0 { self.refractive_index } else { 1.5 };
+            Arc::new(Dielectric { refractive_index })
+        } else if self.specular.max() > self.diffuse.max() {
+            // Phong exponent to a roughness in [0, 1].
+            let fuzz = (2.0 / (self.shininess.max(0.0) + 2.0)).sqrt();
+            Arc::new(Metal {
+                albedo: texture(self.specular, &self.specular_map)?,
+                fuzz,
+            })
+        } else {
+            Arc::new(Lambertian {
+                albedo: texture(self.diffuse, &self.diffuse_map)?,
+            })
+        };
+        Ok(material)
+    }
+}
+
+// Indices into the position, uv and normal arrays for one face corner.
+type Corner = (usize, Option<usize>, Option<usize>);
+
+struct MeshBuilder {
+    group: String,
+    material: String,
+    corners: HashMap<Corner, u32>,
+    vertices: Vec<Corner>,
+    indices: Vec<[u32; 3]>,
+}
+
+impl MeshBuilder {
+    fn new(group: &str, material: &str) -> Self {
+        Self {
+            group: group.to_string(),
+            material: material.to_string(),
+            corners: HashMap::new(),
+            vertices: Vec::new(),
+            indices: Vec::new(),
+        }
+    }
+
+    fn push_face(&mut self, corners: [Corner; 3]) {
+        let mut face = [0; 3];
+        for (index, corner) in face.iter_mut().zip(corners.iter()) {
+            let vertices = &mut self.vertices;
+            *index = *self.corners.entry(*corner).or_insert_with(|| {
+                vertices.push(*corner);
+                (vertices.len() - 1) as u32
+            });
+        }
+        self.indices.push(face);
+    }
+
+    fn build(self, positions: &[Vec3], uvs: &[Vec2], normals: &[Vec3], material: Arc<dyn Material + Sync + Send>) -> Mesh {
+        // Normals and uvs are only kept if every vertex in the mesh has one.
+        let has_uvs = self.vertices.iter().all(|(_, vt, _)| vt.is_some());
+        let has_normals = self.vertices.iter().all(|(_, _, vn)| vn.is_some());
+        Mesh::new(
+            self.vertices.iter().map(|(v, _, _)| positions[*v]).collect(),
+            if has_normals {
+                self.vertices.iter().map(|(_, _, vn)| normals[vn.unwrap()].normalize()).collect()
+            } else {
+                Vec::new()
+            },
+            if has_uvs {
+                self.vertices.iter().map(|(_, vt, _)| uvs[vt.unwrap()]).collect()
+            } else {
+                Vec::new()
+            },
+            self.indices,
+            material,
+        )
+    }
+}
+
+fn parse_float(token: Option<&&str>) -> Result<f32> {
+    let token = token.ok_or_else(|| anyhow!("Missing value"))?;
+    token.parse().map_err(|_| anyhow!("Invalid number '{}'", token))
+}
+
+fn parse_vec3(args: &[&str]) -> Result<Vec3> {
+    Ok(vec3(parse_float(args.first())?, parse_float(args.get(1))?, parse_float(args.get(2))?))
+}
+
+// OBJ indices are 1-based, and negative indices count back from the most
+// recently defined element.
+fn parse_index(token: &str, count: usize) -> Result<usize> {
+    let index: i64 = token.parse().map_err(|_| anyhow!("Invalid index '{}'", token))?;
+    let resolved = if index < 0 { count as i64 + index } else { index - 1 };
+    if resolved < 0 || resolved >= count as i64 {
+        bail!("Index {} out of range", index);
+    }
+    Ok(resolved as usize)
+}
+
+fn parse_corner(token: &str, num_positions: usize, num_uvs: usize, num_normals: usize) -> Result<Corner> {
+    let mut parts = token.split('/');
+    let v = parse_index(parts.next().unwrap_or(""), num_positions)?;
+    let vt = match parts.next() {
+        Some(t) if !t.is_empty() => Some(parse_index(t, num_uvs)?),
+        _ => None,
+    };
+    let vn = match parts.next() {
+        Some(t) if !t.is_empty() => Some(parse_index(t, num_normals)?),
+        _ => None,
+    };
+    Ok((v, vt, vn))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hitable::HitRecord;
+    use crate::ray::Ray;
+    use crate::sampler::Sampler;
+
+    // Writes `files` to a directory of their own and loads the first.
+    fn load_files(test: &str, files: &[(&str, &str)]) -> Result<Vec<Mesh>> {
+        let dir = std::env::temp_dir().join(format!("rray-obj-{}-{}", std::process::id(), test));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+        meshes(&dir.join(files[0].0))
+    }
+
+    const SQUARE: &str = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n";
+
+    #[test]
+    fn polygons_are_fanned() {
+        let obj = "v 0 0 0\nv 1 0 0\nv 2 1 0\nv 1 2 0\nv 0 1 0\nf 1 2 3 4 5\n";
+        let meshes = load_files("fan", &[("fan.obj", obj)]).unwrap();
+        assert_eq!(meshes.len(), 1);
+        assert_eq!(meshes[0].indices, vec![[0, 1, 2], [0, 2, 3], [0, 3, 4]]);
+        assert_eq!(meshes[0].positions[4], vec3(0.0, 1.0, 0.0));
+
+        let obj = format!("{}f 1 2 3 4\n", SQUARE);
+        let meshes = load_files("quad", &[("quad.obj", &obj)]).unwrap();
+        assert_eq!(meshes[0].indices, vec![[0, 1, 2], [0, 2, 3]]);
+    }
+
+    #[test]
+    fn negative_indices_count_back() {
+        let obj = format!("{}f -4 -3 -2\nv 5 5 5\nf -1 -4 -3\n", SQUARE);
+        let meshes = load_files("negative", &[("negative.obj", &obj)]).unwrap();
+        let mesh = &meshes[0];
+        let face = |f: usize| mesh.indices[f].map(|i| mesh.positions[i as usize]);
+        assert_eq!(face(0), [vec3(0.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0), vec3(1.0, 1.0, 0.0)]);
+        assert_eq!(face(1), [vec3(5.0, 5.0, 5.0), vec3(1.0, 0.0, 0.0), vec3(1.0, 1.0, 0.0)]);
+        assert!(load_files("out_of_range", &[("out_of_range.obj", &format!("{}f -5 1 2\n", SQUARE))]).is_err());
+    }
+
+    #[test]
+    fn normal_and_uv_only_corners() {
+        let obj = format!("{}vn 0 0 2\nf 1//1 2//1 3//1\n", SQUARE);
+        let meshes = load_files("normals", &[("normals.obj", &obj)]).unwrap();
+        assert_eq!(meshes[0].normals, vec![vec3(0.0, 0.0, 1.0); 3]);
+        assert!(meshes[0].uvs.is_empty());
+
+        let obj = format!("{}vt 0.25 0.5\nvt 1 0\nf 1/1 2/2 3/1\n", SQUARE);
+        let meshes = load_files("uvs", &[("uvs.obj", &obj)]).unwrap();
+        assert_eq!(meshes[0].uvs, vec![vec2(0.25, 0.5), vec2(1.0, 0.0), vec2(0.25, 0.5)]);
+        assert!(meshes[0].normals.is_empty());
+    }
+
+    // A hit on the front of a face in the xy plane, for looking at
+    // materials.
+    fn hit(material: &Arc<dyn Material + Sync + Send>) -> HitRecord {
+        HitRecord::new(1.0, vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0), material.clone())
+    }
+
+    fn is_diffuse(material: &Arc<dyn Material + Sync + Send>) -> bool {
+        let ray = Ray {
+            origin: vec3(0.0, 0.0, 1.0),
+            direction: vec3(0.0, 0.0, -1.0),
+            time: 0.0,
+            free_flight: 0.0,
+        };
+        material.pdf(&ray, &hit(material), &vec3(0.0, 0.0, 1.0)) > 0.0
+    }
+
+    #[test]
+    fn usemtl_switches_material() {
+        let obj = format!("mtllib two.mtl\n{}usemtl red\nf 1 2 3\nusemtl blue\nf 1 3 4\nusemtl red\nf 2 3 4\n", SQUARE);
+        let mtl = "newmtl red\nKd 1 0 0\nnewmtl blue\nKd 0 0 1\n";
+        let meshes = load_files("usemtl", &[("two.obj", &obj), ("two.mtl", mtl)]).unwrap();
+        let albedos: Vec<Vec3> = meshes.iter().map(|m| m.material.albedo(&hit(&m.material))).collect();
+        assert_eq!(albedos, vec![vec3(1.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0), vec3(1.0, 0.0, 0.0)]);
+        assert!(meshes.iter().all(|m| m.indices.len() == 1));
+    }
+
+    // Always refracts rather than reflecting off glass.
+    struct High;
+
+    impl Sampler for High {
+        fn next_1d(&mut self) -> f32 {
+            0.99
+        }
+    }
+
+    #[test]
+    fn mtl_parameters_pick_materials() {
+        let obj = format!(
+            "mtllib look.mtl\n{}usemtl matte\nf 1 2 3\nusemtl shiny\nf 1 2 3\nusemtl glass\nf 1 2 3\n",
+            SQUARE
+        );
+        let mtl = "newmtl matte\nKd 0.5 0.6 0.7\nKs 0.1 0.1 0.1\n\
+                   newmtl shiny\nKd 0.1 0.1 0.1\nKs 0.9 0.8 0.7\nNs 200\n\
+                   newmtl glass\nKd 0.5 0.5 0.5\nNi 1.3\nd 0.2\n";
+        let meshes = load_files("mtl", &[("look.obj", &obj), ("look.mtl", mtl)]).unwrap();
+        let (matte, shiny, glass) = (&meshes[0].material, &meshes[1].material, &meshes[2].material);
+
+        assert!(is_diffuse(matte));
+        assert_eq!(matte.albedo(&hit(matte)), vec3(0.5, 0.6, 0.7));
+
+        assert!(!is_diffuse(shiny));
+        assert_eq!(shiny.albedo(&hit(shiny)), vec3(0.9, 0.8, 0.7));
+
+        assert!(!is_diffuse(glass));
+        assert_eq!(glass.albedo(&hit(glass)), vec3(1.0, 1.0, 1.0));
+        // Leaving the glass, the sideways part of the direction grows by the
+        // refractive index.
+        let mut rec = hit(glass);
+        rec.front_face = false;
+        let ray = Ray {
+            origin: vec3(0.0, 0.0, -1.0),
+            direction: vec3(0.2, 0.0, 0.96f32.sqrt()),
+            time: 0.0,
+            free_flight: 0.0,
+        };
+        let scattered = glass.scatter(&ray, &rec, &mut High).unwrap();
+        assert!((scattered.ray.direction.x - 0.2 * 1.3).abs() < 1e-4, "{:?}", scattered.ray.direction);
+    }
+
+    #[test]
+    fn missing_material_library_falls_back() {
+        let obj = format!("mtllib nowhere.mtl\n{}usemtl red\nf 1 2 3\n", SQUARE);
+        let meshes = load_files("missing", &[("missing.obj", &obj)]).unwrap();
+        assert!(is_diffuse(&meshes[0].material));
+        assert_eq!(meshes[0].material.albedo(&hit(&meshes[0].material)), vec3(0.8, 0.8, 0.8));
+    }
+}
@@ -0,0 +1,156 @@
+use crate::framebuffer::Framebuffer;
+use crate::renderer::Frame;
+use crate::tonemap::ToneMapper;
+use anyhow::{anyhow, bail, Context, Error, Result};
+use exr::prelude::{AnyChannel, AnyChannels, Encoding, FlatSamples, Image, ImageAttributes, IntegerBounds, Layer};
+use exr::prelude::{LayerAttributes, WritableImage};
+use half::f16;
+use image::{ImageBuffer, Rgb};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+fn to_rgb(pixel: u32) -> [u8; 3] {
+    [(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]
+}
+
+// Writes a render, picking the format from the file extension. PNG and PPM
+// are tone mapped, while EXR and PFM keep the linear radiance.
+pub fn write_image(path: &Path, framebuffer: &Framebuffer, tone_mapper: &ToneMapper, precision: Precision) -> Result<()> {
+    let (width, height) = (framebuffer.width, framebuffer.height);
+    let extension = path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("png") => write_png(path, width, height, &tone_mapper.to_bgra(framebuffer)),
+        Some("ppm") => write_ppm(path, width, height, &tone_mapper.to_bgra(framebuffer)),
+        Some("exr") => write_exr(path, &[("", framebuffer, precision)]),
+        Some("pfm") => write_pfm(path, framebuffer),
+        _ => bail!("Unsupported output format for {}, expected .png, .ppm, .exr or .pfm", path.display()),
+    }
+}
+
+// Writes a render along with its AOVs, returning every file written. EXR
+// files hold the AOVs as extra layers. Other formats get a file per AOV next
+// to the image, "render.albedo.png" and so on, with PNG and PPM showing a
+// visualisation of each rather than the raw values.
+pub fn write_frame(path: &Path, frame: &Frame, tone_mapper: &ToneMapper, precision: Precision) -> Result<Vec<PathBuf>> {
+    let extension = path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase());
+    if extension.as_deref() == Some("exr") {
+        let mut layers = vec![("", &frame.beauty, precision)];
+        for (aov, framebuffer) in frame.aovs.iter() {
+            // Counts need every bit of a float to come back out intact.
+            let precision = if aov.is_count() { Precision::Float } else { precision };
+            layers.push((aov.name(), framebuffer, precision));
+        }
+        write_exr(path, &layers)?;
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    write_image(path, &frame.beauty, tone_mapper, precision)?;
+    let mut written = vec![path.to_path_buf()];
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    for (aov, framebuffer) in frame.aovs.iter() {
+        let (width, height) = (framebuffer.width, framebuffer.height);
+        let aov_path = path.with_file_name(format!("{}.{}.{}", stem, aov.name(), extension.as_deref().unwrap_or("")));
+        match extension.as_deref() {
+            Some("png") => write_png(&aov_path, width, height, &aov.to_bgra(framebuffer))?,
+            Some("ppm") => write_ppm(&aov_path, width, height, &aov.to_bgra(framebuffer))?,
+            _ => write_pfm(&aov_path, framebuffer)?,
+        }
+        written.push(aov_path);
+    }
+    Ok(written)
+}
+
+pub fn write_png(path: &Path, width: usize, height: usize, buf: &[u32]) -> Result<()> {
+    let pixels = buf.iter().flat_map(|&p| to_rgb(p)).collect();
+    let image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_raw(width as u32, height as u32, pixels)
+        .context("Buffer doesn't match the image dimensions")?;
+    image
+        .save(path)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+// Binary (P6) PPM.
+pub fn write_ppm(path: &Path, width: usize, height: usize, buf: &[u32]) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    write!(writer, "P6\n{} {}\n255\n", width, height)?;
+    for &pixel in buf {
+        writer.write_all(&to_rgb(pixel))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Bits per channel in an EXR.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Precision {
+    Half,
+    Float,
+}
+
+impl FromStr for Precision {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(match s {
+            "half" => Precision::Half,
+            "float" => Precision::Float,
+            _ => bail!("unknown precision '{}', expected \"half\" or \"float\"", s),
+        })
+    }
+}
+
+// Writes each framebuffer as a layer of RGB channels in a single part, named
+// the way compositors expect, "<layer>.R" and so on. A layer with an empty
+// name gets plain R, G and B. Every framebuffer must be the same size.
+pub fn write_exr(path: &Path, layers: &[(&str, &Framebuffer, Precision)]) -> Result<()> {
+    let (width, height) = match layers.first() {
+        Some((_, f, _)) => (f.width, f.height),
+        None => bail!("No layers to write to {}", path.display()),
+    };
+    let mut channels = Vec::new();
+    for (name, framebuffer, precision) in layers {
+        if (framebuffer.width, framebuffer.height) != (width, height) {
+            bail!("Layer '{}' doesn't match the size of the others", name);
+        }
+        for (i, channel) in ["R", "G", "B"].iter().enumerate() {
+            let values = framebuffer.pixels.iter().map(|p| p[i]);
+            let samples = match precision {
+                Precision::Half => FlatSamples::F16(values.map(f16::from_f32).collect()),
+                Precision::Float => FlatSamples::F32(values.collect()),
+            };
+            let channel_name = if name.is_empty() { channel.to_string() } else { format!("{}.{}", name, channel) };
+            channels.push(AnyChannel::new(channel_name.as_str(), samples));
+        }
+    }
+
+    let layer = Layer::new(
+        (width, height),
+        LayerAttributes::default(),
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(channels.into()),
+    );
+    let image = Image::new(ImageAttributes::new(IntegerBounds::from_dimensions((width, height))), layer);
+    image
+        .write()
+        .to_file(path)
+        .map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e))
+}
+
+// Colour PFM, which is little-endian floats from the bottom row up.
+pub fn write_pfm(path: &Path, framebuffer: &Framebuffer) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    write!(writer, "PF\n{} {}\n-1.0\n", framebuffer.width, framebuffer.height)?;
+    for row in framebuffer.pixels.chunks(framebuffer.width).rev() {
+        for pixel in row {
+            for c in pixel.iter() {
+                writer.write_all(&c.to_le_bytes())?;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
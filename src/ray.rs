@@ -1,8 +1,15 @@
 use glm::Vec3;
 
+#[derive(Clone, Copy)]
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
+    // When the ray was cast, for motion blur.
+    pub time: f32,
+    // How far the ray gets into a medium before scattering, as a sample in
+    // [0, 1) that each medium it crosses scrambles for itself. Drawn for each
+    // ray that's traced, and zero otherwise.
+    pub free_flight: f32,
 }
 
 impl Ray {
@@ -1,81 +1,762 @@
+use crate::aov::Aov;
 use crate::camera::Camera;
-use crate::hitable::{Hitable, HitableList};
+use crate::filter::{Film, Filter};
+use crate::framebuffer::Framebuffer;
+use crate::hitable::{HitRecord, Hitable};
 use crate::ray::Ray;
-use glm::{vec3, Vec3};
-use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
-use rand::prelude::*;
-use rayon::prelude::*;
+use crate::sampler::{Sampler, SamplerKind};
+use crate::tile::{self, Tile, TileOrder};
+use anyhow::Result;
+use glm::{vec2, vec3, Vec2, Vec3};
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::ThreadPoolBuilder;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread;
 
-#[cfg(debug_assertions)]
-const NUM_SAMPLES: u32 = 64;
-#[cfg(not(debug_assertions))]
-const NUM_SAMPLES: u32 = 256;
-const MAX_DEPTH: u32 = 16;
+pub struct RenderSettings {
+    pub width: usize,
+    pub height: usize,
+    // The most samples a pixel gets. With a threshold, pixels stop early
+    // once the standard error of their luminance falls below that fraction
+    // of it, but not before `min_samples`.
+    pub samples: u32,
+    pub min_samples: u32,
+    pub threshold: Option<f32>,
+    // Bounces before Russian roulette starts, and the most a path can make.
+    pub min_depth: u32,
+    pub max_depth: u32,
+    // Zero uses one thread per core.
+    pub threads: usize,
+    pub seed: u64,
+    pub sampler: SamplerKind,
+    pub filter: Filter,
+    // Pixels are rendered in square tiles this many across, started in
+    // this order.
+    pub tile_size: usize,
+    pub tile_order: TileOrder,
+    pub background: Background,
+    // Passes to render alongside the image.
+    pub aovs: Vec<Aov>,
+}
+
+// A rendered image and its AOVs, in the order they were asked for.
+pub struct Frame {
+    pub beauty: Framebuffer,
+    pub aovs: Vec<(Aov, Framebuffer)>,
+}
 
 pub fn vec_squared_length(vec: &Vec3) -> f32 {
     vec.x * vec.x + vec.y * vec.y + vec.z * vec.z
 }
 
-pub fn random_in_unit_sphere(rng: &mut ThreadRng) -> Vec3 {
-    let mut v;
+// These map sample values straight onto the shape rather than rejecting
+// points outside it, so each takes a fixed number of them.
+pub fn random_in_unit_sphere(sampler: &mut dyn Sampler) -> Vec3 {
+    let radius = sampler.next_1d().cbrt();
+    radius * random_unit_vector(sampler)
+}
+
+pub fn random_unit_vector(sampler: &mut dyn Sampler) -> Vec3 {
+    let u = sampler.next_2d();
+    let z = 1.0 - 2.0 * u.x;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.y;
+    vec3(r * phi.cos(), r * phi.sin(), z)
+}
+
+// In the xy plane.
+pub fn random_in_unit_disk(sampler: &mut dyn Sampler) -> Vec3 {
+    let u = sampler.next_2d();
+    let r = u.x.sqrt();
+    let theta = 2.0 * PI * u.y;
+    vec3(r * theta.cos(), r * theta.sin(), 0.0)
+}
+
+// What a ray sees when it escapes the scene.
+#[derive(Clone, Copy)]
+pub enum Background {
+    Sky,
+    Colour(Vec3),
+}
+
+impl Background {
+    pub fn colour(&self, ray: &Ray) -> Vec3 {
+        match self {
+            Background::Sky => {
+                let direction = ray.direction.normalize();
+                let time = 0.5 * (direction.y + 1.0);
+                (1.0 - time) * vec3(1.0, 1.0, 1.0) + time * vec3(0.5, 0.7, 1.0)
+            }
+            Background::Colour(c) => *c,
+        }
+    }
+}
+
+// Lights are sampled one at a time, so the density of a direction is the
+// average over every light.
+fn light_pdf(lights: &[&(dyn Hitable + Sync)], origin: &Vec3, direction: &Vec3) -> f32 {
+    if lights.is_empty() {
+        return 0.0;
+    }
+    lights.iter().map(|l| l.pdf(origin, direction)).sum::<f32>() / lights.len() as f32
+}
+
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b > 0.0 {
+        a / (a + b)
+    } else {
+        0.0
+    }
+}
+
+// Next-event estimation: direct light at a hit, found by casting a shadow ray
+// towards a randomly chosen light.
+fn sample_lights<T: Hitable>(
+    ray: &Ray,
+    rec: &HitRecord,
+    world: &T,
+    lights: &[&(dyn Hitable + Sync)],
+    sampler: &mut dyn Sampler,
+) -> Vec3 {
+    let choice = (sampler.next_1d() * lights.len() as f32) as usize;
+    let light = lights[choice.min(lights.len() - 1)];
+    let direction = light.sample_direction(&rec.position, sampler);
+    let pdf = light_pdf(lights, &rec.position, &direction);
+    if pdf <= 0.0 {
+        return vec3(0.0, 0.0, 0.0);
+    }
+    let f = rec.material.eval(ray, rec, &direction);
+    if f == vec3(0.0, 0.0, 0.0) {
+        return vec3(0.0, 0.0, 0.0);
+    }
+    let shadow_ray = Ray {
+        origin: rec.position,
+        direction,
+        time: ray.time,
+        free_flight: sampler.next_1d(),
+    };
+    match world.hit(&shadow_ray, 0.001, f32::MAX) {
+        Some(light_rec) => {
+            let weight = power_heuristic(pdf, rec.material.pdf(ray, rec, &direction));
+            f.component_mul(&light_rec.material.emitted(&light_rec)) * (weight / pdf)
+        }
+        None => vec3(0.0, 0.0, 0.0),
+    }
+}
+
+// Follows a path from the camera, adding up light found along the way
+// weighted by how much of it makes it back. Paths end when they escape, get
+// absorbed or reach `max_depth` bounces, and after `min_depth` bounces are
+// cut short at random, in proportion to how little they still carry, with
+// survivors weighted up to make up for the ones that weren't followed.
+// `ray` should come with its `free_flight` sample already drawn.
+pub fn colour<T: Hitable>(
+    ray: &Ray,
+    world: &T,
+    lights: &[&(dyn Hitable + Sync)],
+    settings: &RenderSettings,
+    sampler: &mut dyn Sampler,
+) -> Vec3 {
+    let mut radiance = vec3(0.0, 0.0, 0.0);
+    let mut throughput = vec3(1.0, 1.0, 1.0);
+    let mut ray = *ray;
+    // The density the ray was scattered with, if its origin also sampled the
+    // lights, so that light found both ways is weighted between them.
+    let mut bsdf_pdf = None;
+    let mut depth = 0;
     loop {
-        v = 2.0 * vec3(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>()) - vec3(1.0, 1.0, 1.0);
-        if !(vec_squared_length(&v) >= 1.0) {
+        let rec = match world.hit(&ray, 0.001, f32::MAX) {
+            Some(rec) => rec,
+            None => {
+                radiance += throughput.component_mul(&settings.background.colour(&ray));
+                break;
+            }
+        };
+        let mut emitted = rec.material.emitted(&rec);
+        if let Some(pdf) = bsdf_pdf {
+            emitted *= power_heuristic(pdf, light_pdf(lights, &ray.origin, &ray.direction));
+        }
+        radiance += throughput.component_mul(&emitted);
+        if depth >= settings.max_depth {
             break;
         }
+
+        let scattered = match rec.material.scatter(&ray, &rec, sampler) {
+            Some(scattered) => scattered,
+            None => break,
+        };
+        bsdf_pdf = scattered.pdf.filter(|_| !lights.is_empty());
+        if bsdf_pdf.is_some() {
+            radiance += throughput.component_mul(&sample_lights(&ray, &rec, world, lights, sampler));
+        }
+        throughput = throughput.component_mul(&scattered.attenuation);
+        ray = Ray {
+            free_flight: sampler.next_1d(),
+            ..scattered.ray
+        };
+        depth += 1;
+
+        if depth >= settings.min_depth {
+            let survival = throughput.max().min(1.0);
+            if survival <= 0.0 || sampler.next_1d() >= survival {
+                break;
+            }
+            throughput /= survival;
+        }
     }
-    v
+    radiance
 }
 
-pub fn colour<T: Hitable>(ray: &Ray, world: &T, rng: &mut ThreadRng, depth: u32) -> Vec3 {
-    if let Some(rec) = world.hit(ray, 0.001, f32::MAX) {
-        if depth < MAX_DEPTH {
-            let scattered = rec.material.scatter(&ray, &rec, rng);
-            scattered
-                .attenuation
-                .component_mul(&colour(&scattered.ray, world, rng, depth + 1))
-        } else {
-            vec3(0.0, 0.0, 0.0)
+// Traces camera sample number `sample` through the pixel at `screen_pos`,
+// counting along rows from the top. Returns where on the image it landed, in
+// pixels across and down, its radiance, and its AOVs. Every sample gets its
+// own sampler, so a pixel comes out the same however its samples are split
+// up.
+fn sample_pixel<T: Hitable>(
+    settings: &RenderSettings,
+    camera: &(dyn Camera + Sync),
+    world: &T,
+    lights: &[&(dyn Hitable + Sync)],
+    screen_pos: usize,
+    sample: u32,
+) -> (Vec2, Vec3, Vec<Vec3>) {
+    let (width, height) = (settings.width, settings.height);
+    let i = height - 1 - screen_pos / width;
+    let j = screen_pos % width;
+    let sampler = &mut settings.sampler.sampler(settings.seed, j as u32, (screen_pos / width) as u32, sample, settings.samples);
+    let jitter = sampler.next_2d();
+    let u = ((j as f32) + jitter.x) / (width as f32);
+    let v = ((i as f32) + 1.0 - jitter.y) / (height as f32);
+    // The AOVs and the path share the first ray, so they see the same thing
+    // even inside a medium.
+    let r = camera.get_ray(sampler, u, v).map(|r| Ray {
+        free_flight: sampler.next_1d(),
+        ..r
+    });
+    let mut aovs = Vec::new();
+    if settings.aovs.iter().any(|a| a.is_sampled()) {
+        let rec = r.as_ref().and_then(|r| world.hit(r, 0.001, f32::MAX));
+        for aov in settings.aovs.iter().filter(|a| a.is_sampled()) {
+            aovs.push(r.as_ref().map_or(vec3(0.0, 0.0, 0.0), |r| aov.sample(r, rec.as_ref())));
         }
-    } else {
-        let direction = ray.direction.normalize();
-        let time = 0.5 * (direction.y + 1.0);
-        (1.0 - time) * vec3(1.0, 1.0, 1.0) + time * vec3(0.5, 0.7, 1.0)
     }
+    let c = match r {
+        Some(r) => colour(&r, world, lights, settings, sampler),
+        None => vec3(0.0, 0.0, 0.0),
+    };
+    let film = vec2(j as f32 + jitter.x, (screen_pos / width) as f32 + jitter.y);
+    (film, c, aovs)
+}
+
+fn luminance(c: &Vec3) -> f32 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
 }
 
-fn to_bgra(r: u32, g: u32, b: u32) -> u32 {
-    255 << 24 | r << 16 | g << 8 | b
+// Stops relative error blowing up in pixels that are all but black.
+const MIN_LUMINANCE: f32 = 0.01;
+
+// Everything gathered from one pixel's own samples so far, not counting what
+// they add to its neighbours through the filter. Its luminance is tracked
+// with Welford's method, to estimate how far off the pixel's mean might
+// still be.
+#[derive(Clone)]
+struct Pixel {
+    // Just the sampled AOVs, in the order they were asked for.
+    aovs: Vec<Vec3>,
+    samples: u32,
+    mean: f32,
+    m2: f32,
 }
 
-pub fn render(width: usize, height: usize, camera: Camera, world: HitableList) -> Vec<u32> {
-    let pb = ProgressBar::new((width * height) as u64);
+impl Pixel {
+    fn new(settings: &RenderSettings) -> Self {
+        Self {
+            aovs: vec![vec3(0.0, 0.0, 0.0); settings.aovs.iter().filter(|a| a.is_sampled()).count()],
+            samples: 0,
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+
+    // IDs aren't summed but come from the first sample.
+    fn add(&mut self, settings: &RenderSettings, c: &Vec3, aovs: Vec<Vec3>) {
+        let sampled = settings.aovs.iter().filter(|a| a.is_sampled());
+        for ((value, aov), sample) in self.aovs.iter_mut().zip(sampled).zip(aovs) {
+            if !aov.is_id() {
+                *value += sample;
+            } else if self.samples == 0 {
+                *value = sample;
+            }
+        }
+        self.samples += 1;
+        let y = luminance(c);
+        let delta = y - self.mean;
+        self.mean += delta / self.samples as f32;
+        self.m2 += delta * (y - self.mean);
+    }
+
+    // Standard error of the mean luminance, relative to the mean.
+    fn error(&self) -> f32 {
+        if self.samples < 2 {
+            return f32::INFINITY;
+        }
+        let variance = self.m2 / (self.samples - 1) as f32;
+        (variance / self.samples as f32).sqrt() / self.mean.max(MIN_LUMINANCE)
+    }
+
+    fn done(&self, settings: &RenderSettings) -> bool {
+        self.samples >= settings.samples
+            || settings
+                .threshold
+                .is_some_and(|t| self.samples >= settings.min_samples && self.error() <= t)
+    }
+}
+
+// Divides the sums down to averages. AOVs are plain averages over each
+// pixel's own samples, as filtering would blur IDs into each other.
+fn average(settings: &RenderSettings, pixels: &[Pixel], film: &Film) -> Frame {
+    let (width, height) = (settings.width, settings.height);
+    let mut frame = Frame {
+        beauty: Framebuffer::new(width, height),
+        aovs: settings.aovs.iter().map(|&a| (a, Framebuffer::new(width, height))).collect(),
+    };
+    for (k, pixel) in pixels.iter().enumerate() {
+        let scale = if pixel.samples > 0 { 1.0 / pixel.samples as f32 } else { 0.0 };
+        frame.beauty.pixels[k] = film.get(k);
+        let mut values = pixel.aovs.iter();
+        for (aov, framebuffer) in frame.aovs.iter_mut() {
+            framebuffer.pixels[k] = match aov {
+                Aov::Samples => vec3(1.0, 1.0, 1.0) * pixel.samples as f32,
+                _ if aov.is_id() => *values.next().unwrap(),
+                _ => scale * values.next().unwrap(),
+            };
+        }
+    }
+    frame
+}
+
+fn log_samples(settings: &RenderSettings, pixels: &[Pixel]) {
+    if settings.threshold.is_some() {
+        let total: u64 = pixels.iter().map(|p| p.samples as u64).sum();
+        info!("Took {:.1} samples per pixel on average", total as f64 / pixels.len() as f64);
+    }
+}
+
+fn progress_bar(length: u64, unit: &str) -> ProgressBar {
+    let pb = ProgressBar::new(length);
     pb.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{elapsed_precise}, {percent}%] [{bar:40.cyan/blue}] {count}/{total_count} eta: {eta}, {per_sec} pixels/sec")
+        .template(&format!("{{spinner:.green}} [{{elapsed_precise}}, {{percent}}%] [{{bar:40.cyan/blue}}] {{count}}/{{total_count}} eta: {{eta}}, {{per_sec}} {}/sec", unit))
         .progress_chars("#>-"));
+    pb
+}
+
+// Runs `trace` over every pixel a tile at a time, `passes` times over, with
+// each tile splatting into its own film reaching as far past it as the
+// filter does. Each thread takes the next tile in order as soon as it's
+// free, and the tiles are added up on this thread in that same order, so the
+// sums come out the same on any number of threads. `on_tile` is given each
+// tile as soon as it's finished, along with the film so far, and stops any
+// more being started if it returns false. `after_pass` says whether to go round again. Returns every
+// pixel, in the order they appear in the image, along with the film.
+fn trace_tiles<F, G, H>(
+    settings: &RenderSettings,
+    passes: u32,
+    trace: F,
+    mut on_tile: G,
+    mut after_pass: H,
+) -> Result<(Vec<Pixel>, Film)>
+where
+    F: Fn(usize, &mut Pixel, &mut Film) + Sync,
+    G: FnMut(&Tile, &Film) -> bool,
+    H: FnMut() -> bool,
+{
+    let (width, height) = (settings.width, settings.height);
+    let pool = ThreadPoolBuilder::new().num_threads(settings.threads).build()?;
+    let tiles = tile::tiles(width, height, settings.tile_size, settings.tile_order);
+    let reach = (settings.filter.radius + 0.5).ceil() as isize;
+    // Each tile's pixels are kept together, so tracing one only locks its
+    // own.
+    let work: Vec<Mutex<Vec<Pixel>>> = tiles
+        .iter()
+        .map(|tile| Mutex::new(vec![Pixel::new(settings); tile.width * tile.height]))
+        .collect();
+    let mut film = Film::new(settings.filter, width, height, 0, 0, width, height);
+    // Tiles are shown from a copy they're added to as soon as they're done,
+    // so a slow tile doesn't hold up the ones after it. That adds up in a
+    // different order each time, so only `film` is kept.
+    let mut preview = Film::new(settings.filter, width, height, 0, 0, width, height);
+    let next = AtomicUsize::new(0);
+    let cancelled = AtomicBool::new(false);
+    let worker = |sender: Sender<Option<(usize, Film)>>| loop {
+        let t = next.fetch_add(1, Ordering::Relaxed);
+        if t >= tiles.len() || cancelled.load(Ordering::Relaxed) {
+            break;
+        }
+        let tile = &tiles[t];
+        let mut tile_film = Film::new(
+            settings.filter,
+            width,
+            height,
+            tile.x as isize - reach,
+            tile.y as isize - reach,
+            tile.width + 2 * reach as usize,
+            tile.height + 2 * reach as usize,
+        );
+        for (k, pixel) in tile.positions(width).zip(work[t].lock().unwrap().iter_mut()) {
+            trace(k, pixel, &mut tile_film);
+        }
+        if sender.send(Some((t, tile_film))).is_err() {
+            break;
+        }
+    };
 
-    (0..width * height)
-        .into_par_iter()
-        .progress_with(pb)
-        .map_init(
-            || thread_rng(),
-            |mut rng, screen_pos| {
-                let mut c = vec3(0.0, 0.0, 0.0);
-                let i = height - 1 - screen_pos / width;
-                let j = screen_pos % width;
-                for _ in 0..NUM_SAMPLES {
-                    let u = ((j as f32) + rng.gen::<f32>()) / (width as f32);
-                    let v = ((i as f32) + rng.gen::<f32>()) / (height as f32);
-                    let r = camera.get_ray(&mut rng, u, v);
-                    c += colour(&r, &world, &mut rng, 0);
+    // One thread starts each pass on the pool and says when it's over,
+    // leaving this one free to gather the tiles up and hand them on.
+    let (start, starts) = mpsc::channel();
+    let (sender, receiver) = mpsc::channel();
+    let (worker, next) = (&worker, &next);
+    thread::scope(|s| {
+        s.spawn(move || {
+            for () in starts {
+                next.store(0, Ordering::Relaxed);
+                pool.scope(|scope| {
+                    for _ in 0..pool.current_num_threads() {
+                        let sender = sender.clone();
+                        scope.spawn(move |_| worker(sender));
+                    }
+                });
+                if sender.send(None).is_err() {
+                    break;
                 }
-                c = (1.0 / NUM_SAMPLES as f32) * c;
-                let ir = (255.99 * c.x.sqrt()) as u32;
-                let ig = (255.99 * c.y.sqrt()) as u32;
-                let ib = (255.99 * c.z.sqrt()) as u32;
+            }
+        });
+        for _ in 0..passes {
+            if start.send(()).is_err() {
+                break;
+            }
+            let mut finished = HashMap::new();
+            let mut merged = 0;
+            // Everything that was started is added in, even once cancelled,
+            // so the film always matches the pixels.
+            while let Ok(Some((t, tile_film))) = receiver.recv() {
+                preview.merge(&tile_film);
+                if !cancelled.load(Ordering::Relaxed) && !on_tile(&tiles[t], &preview) {
+                    cancelled.store(true, Ordering::Relaxed);
+                }
+                finished.insert(t, tile_film);
+                while let Some(tile_film) = finished.remove(&merged) {
+                    film.merge(&tile_film);
+                    merged += 1;
+                }
+            }
+            if cancelled.load(Ordering::Relaxed) || !after_pass() {
+                break;
+            }
+        }
+        drop(start);
+    });
+
+    let mut pixels = vec![Pixel::new(settings); width * height];
+    for (tile, tile_pixels) in tiles.iter().zip(work) {
+        for (k, pixel) in tile.positions(width).zip(tile_pixels.into_inner().unwrap()) {
+            pixels[k] = pixel;
+        }
+    }
+    Ok((pixels, film))
+}
+
+// What to show for `tile`: the filtered image so far over it and as far
+// around it as its samples spread, as the tiles beside it will have changed
+// too.
+fn painted(settings: &RenderSettings, tile: &Tile, film: &Film) -> (Tile, Vec<Vec3>) {
+    let spread = (settings.filter.radius - 0.5).ceil().max(0.0) as usize;
+    let area = tile.grow(spread, settings.width, settings.height);
+    let values = area.positions(settings.width).map(|k| film.get(k)).collect();
+    (area, values)
+}
 
-                to_bgra(ir, ig, ib)
+// Renders linear radiance, to be tone mapped for display. Each tile is handed
+// to `on_tile` as soon as it's finished, whatever order they were started in,
+// along with its pixels and any around it the filter reached. Stops early if `on_tile`
+// returns false, leaving any tiles that hadn't been started black.
+pub fn render<T, F>(settings: &RenderSettings, camera: &(dyn Camera + Sync), world: T, mut on_tile: F) -> Result<Frame>
+where
+    T: Hitable + Sync,
+    F: FnMut(&Tile, &[Vec3]) -> bool,
+{
+    let (width, height) = (settings.width, settings.height);
+    let pb = progress_bar((width * height) as u64, "pixels");
+
+    let mut lights = Vec::new();
+    world.lights(&mut lights);
+    info!("Sampling {} lights", lights.len());
+
+    let (pixels, film) = trace_tiles(
+        settings,
+        1,
+        |screen_pos, pixel, tile_film| {
+            while !pixel.done(settings) {
+                let (position, c, aovs) = sample_pixel(settings, camera, &world, &lights, screen_pos, pixel.samples);
+                tile_film.splat(position.x, position.y, &c);
+                pixel.add(settings, &c, aovs);
+            }
+        },
+        |tile, film| {
+            pb.inc((tile.width * tile.height) as u64);
+            let (area, values) = painted(settings, tile, film);
+            on_tile(&area, &values)
+        },
+        || true,
+    )?;
+    pb.finish();
+    log_samples(settings, &pixels);
+    Ok(average(settings, &pixels, &film))
+}
+
+// Renders one sample per pixel at a time, a tile at a time within each pass,
+// handing each tile to `on_tile` as it's finished like `render` does. Pixels
+// that are done sit passes out. Stops once they all are, or as soon as
+// `on_tile` returns false, and returns the image so far.
+pub fn render_progressive<T, F>(
+    settings: &RenderSettings,
+    camera: &(dyn Camera + Sync),
+    world: T,
+    mut on_tile: F,
+) -> Result<Frame>
+where
+    T: Hitable + Sync,
+    F: FnMut(&Tile, &[Vec3]) -> bool,
+{
+    let pb = progress_bar(settings.samples as u64, "samples");
+
+    let mut lights = Vec::new();
+    world.lights(&mut lights);
+    info!("Sampling {} lights", lights.len());
+
+    let unfinished = AtomicBool::new(false);
+    let (pixels, film) = trace_tiles(
+        settings,
+        settings.samples,
+        |screen_pos, pixel, tile_film| {
+            if !pixel.done(settings) {
+                let (position, c, aovs) = sample_pixel(settings, camera, &world, &lights, screen_pos, pixel.samples);
+                tile_film.splat(position.x, position.y, &c);
+                pixel.add(settings, &c, aovs);
+                if !pixel.done(settings) {
+                    unfinished.store(true, Ordering::Relaxed);
+                }
+            }
+        },
+        |tile, film| {
+            let (area, values) = painted(settings, tile, film);
+            on_tile(&area, &values)
+        },
+        || {
+            pb.inc(1);
+            unfinished.swap(false, Ordering::Relaxed)
+        },
+    )?;
+    pb.finish();
+    log_samples(settings, &pixels);
+    Ok(average(settings, &pixels, &film))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{Orthographic, Perspective};
+    use crate::filter::FilterKind;
+    use crate::hitable::HitableList;
+    use crate::material::{Dielectric, DiffuseLight, Isotropic, Lambertian, Metal};
+    use crate::medium::ConstantMedium;
+    use crate::sphere::Sphere;
+    use crate::texture::SolidColour;
+    use std::sync::Arc;
+
+    // A little of everything that draws samples: diffuse, glossy and glass
+    // surfaces, a light to sample directly, and a medium.
+    fn world() -> HitableList {
+        let solid = |r, g, b| Arc::new(SolidColour { colour: vec3(r, g, b) });
+        HitableList {
+            list: vec![
+                Box::new(Sphere {
+                    centre: vec3(0.0, -100.5, -1.0),
+                    radius: 100.0,
+                    material: Arc::new(Lambertian { albedo: solid(0.8, 0.8, 0.0) }),
+                }),
+                Box::new(Sphere {
+                    centre: vec3(-1.0, 0.0, -1.0),
+                    radius: 0.5,
+                    material: Arc::new(Dielectric { refractive_index: 1.5 }),
+                }),
+                Box::new(Sphere {
+                    centre: vec3(1.0, 0.0, -1.0),
+                    radius: 0.5,
+                    material: Arc::new(Metal { albedo: solid(0.8, 0.6, 0.2), fuzz: 0.3 }),
+                }),
+                Box::new(ConstantMedium {
+                    boundary: Box::new(Sphere {
+                        centre: vec3(0.0, 0.0, -1.0),
+                        radius: 0.5,
+                        material: Arc::new(Lambertian { albedo: solid(1.0, 1.0, 1.0) }),
+                    }),
+                    density: 2.0,
+                    phase: Arc::new(Isotropic { albedo: solid(0.1, 0.2, 0.5) }),
+                }),
+                Box::new(Sphere {
+                    centre: vec3(0.0, 2.0, -1.0),
+                    radius: 0.5,
+                    material: Arc::new(DiffuseLight { emit: vec3(4.0, 4.0, 4.0) }),
+                }),
+            ],
+        }
+    }
+
+    fn render_with(threads: usize, sampler: SamplerKind, filter: FilterKind) -> Frame {
+        let settings = RenderSettings {
+            width: 16,
+            height: 12,
+            samples: 4,
+            min_samples: 4,
+            threshold: None,
+            min_depth: 3,
+            max_depth: 16,
+            threads,
+            seed: 7,
+            sampler,
+            filter: Filter {
+                kind: filter,
+                radius: filter.default_radius(),
+            },
+            tile_size: 5,
+            tile_order: TileOrder::Hilbert,
+            background: Background::Sky,
+            aovs: vec![Aov::Albedo, Aov::Depth],
+        };
+        let camera = Perspective::new(
+            vec3(0.0, 0.5, 2.0),
+            vec3(0.0, 0.0, -1.0),
+            vec3(0.0, 1.0, 0.0),
+            40.0,
+            16.0 / 12.0,
+            0.1,
+            3.0,
+        );
+        render(&settings, &camera, world().into_bvh(0.0, 1.0), |_, _| true).unwrap()
+    }
+
+    #[test]
+    fn same_image_on_any_number_of_threads() {
+        let samplers = [
+            SamplerKind::Independent,
+            SamplerKind::Stratified,
+            SamplerKind::Halton,
+            SamplerKind::Sobol,
+            SamplerKind::BlueNoise,
+        ];
+        for sampler in samplers {
+            for filter in [FilterKind::Box, FilterKind::Mitchell, FilterKind::Lanczos] {
+                let (one, four) = (render_with(1, sampler, filter), render_with(4, sampler, filter));
+                assert_eq!(one.beauty.pixels, four.beauty.pixels, "{:?} with {:?}", sampler, filter);
+                for ((_, a), (_, b)) in one.aovs.iter().zip(four.aovs.iter()) {
+                    assert_eq!(a.pixels, b.pixels, "{:?} with {:?}", sampler, filter);
+                }
+            }
+        }
+    }
+
+    // Light through two unit spheres of fog one after the other should be cut
+    // down by both. Nothing scatters back, so the one pixel is just the
+    // fraction of the white background that gets through.
+    #[test]
+    fn media_in_series_each_absorb() {
+        let fog = |z| -> Box<dyn Hitable + Sync + Send> {
+            Box::new(ConstantMedium {
+                boundary: Box::new(Sphere {
+                    centre: vec3(0.0, 0.0, z),
+                    radius: 1.0,
+                    material: Arc::new(Lambertian { albedo: Arc::new(SolidColour { colour: vec3(1.0, 1.0, 1.0) }) }),
+                }),
+                density: 0.5,
+                phase: Arc::new(Isotropic { albedo: Arc::new(SolidColour { colour: vec3(0.0, 0.0, 0.0) }) }),
+            })
+        };
+        let settings = RenderSettings {
+            width: 1,
+            height: 1,
+            samples: 8192,
+            min_samples: 8192,
+            threshold: None,
+            min_depth: 0,
+            max_depth: 16,
+            threads: 0,
+            seed: 3,
+            sampler: SamplerKind::Independent,
+            filter: Filter {
+                kind: FilterKind::Box,
+                radius: FilterKind::Box.default_radius(),
+            },
+            tile_size: 16,
+            tile_order: TileOrder::Scanline,
+            background: Background::Colour(vec3(1.0, 1.0, 1.0)),
+            aovs: Vec::new(),
+        };
+        let camera = Orthographic::new(vec3(0.0, 0.0, 5.0), vec3(0.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0), 0.01, 1.0);
+        let world = HitableList { list: vec![fog(0.0), fog(-3.0)] };
+        let frame = render(&settings, &camera, world, |_, _| true).unwrap();
+        // Each sphere is two units through at a density of a half.
+        let transmittance = frame.beauty.pixels[0].x;
+        assert!((transmittance - (-2.0f32).exp()).abs() < 0.02, "{}", transmittance);
+    }
+
+    // A tile that's slow to trace shouldn't stop the ones after it from being
+    // shown. The first tile waits until the second has been.
+    #[test]
+    fn tiles_are_shown_as_they_finish() {
+        let settings = RenderSettings {
+            width: 8,
+            height: 4,
+            samples: 1,
+            min_samples: 1,
+            threshold: None,
+            min_depth: 3,
+            max_depth: 16,
+            threads: 2,
+            seed: 1,
+            sampler: SamplerKind::Independent,
+            filter: Filter {
+                kind: FilterKind::Box,
+                radius: 0.5,
+            },
+            tile_size: 4,
+            tile_order: TileOrder::Scanline,
+            background: Background::Sky,
+            aovs: Vec::new(),
+        };
+        let second_shown = AtomicBool::new(false);
+        let mut shown = Vec::new();
+        trace_tiles(
+            &settings,
+            1,
+            |k, _, _| {
+                let start = std::time::Instant::now();
+                while k % 8 < 4 && !second_shown.load(Ordering::Relaxed) && start.elapsed().as_secs() < 5 {
+                    thread::sleep(std::time::Duration::from_millis(1));
+                }
+            },
+            |tile, _| {
+                shown.push(tile.x);
+                second_shown.store(true, Ordering::Relaxed);
+                true
             },
+            || true,
         )
-        .collect()
+        .unwrap();
+        assert_eq!(shown, vec![4, 0]);
+    }
 }
@@ -0,0 +1,576 @@
+use crate::noise::{hash, to_unit};
+use anyhow::{bail, Error};
+use glm::{vec2, Vec2};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+// Hands out the random numbers for one camera sample. Everything that needs
+// randomness takes one of these, so the numbers a path sees are fixed by the
+// pixel and sample it belongs to, not by which thread traced it or when.
+//
+// Each call is a new dimension. Asking for two values at once lets a sampler
+// spread the pair out over the square, rather than just along each axis.
+pub trait Sampler {
+    // Uniform in [0, 1).
+    fn next_1d(&mut self) -> f32;
+
+    fn next_2d(&mut self) -> Vec2 {
+        let x = self.next_1d();
+        vec2(x, self.next_1d())
+    }
+}
+
+// The largest float below one.
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+// Shifts a value around the unit interval.
+fn rotate(x: f32, offset: f32) -> f32 {
+    let y = x + offset;
+    (y - y.floor()).min(ONE_MINUS_EPSILON)
+}
+
+fn mix(seed: u64, a: u64, b: u64) -> u64 {
+    hash(seed ^ hash(a ^ hash(b)))
+}
+
+// Which sequence samples are drawn from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+    BlueNoise,
+}
+
+impl FromStr for SamplerKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(match s {
+            "independent" => SamplerKind::Independent,
+            "stratified" => SamplerKind::Stratified,
+            "halton" => SamplerKind::Halton,
+            "sobol" => SamplerKind::Sobol,
+            "blue_noise" => SamplerKind::BlueNoise,
+            _ => bail!(
+                "unknown sampler '{}', expected \"independent\", \"stratified\", \"halton\", \"sobol\" or \"blue_noise\"",
+                s
+            ),
+        })
+    }
+}
+
+impl SamplerKind {
+    // The sampler for sample number `sample` of `samples` in the pixel at
+    // column `x` and row `y`.
+    pub fn sampler(&self, seed: u64, x: u32, y: u32, sample: u32, samples: u32) -> PixelSampler {
+        let pixel = (y as u64) << 32 | x as u64;
+        match self {
+            SamplerKind::Independent => PixelSampler::Independent(Independent::new(seed, pixel, sample as u64)),
+            SamplerKind::Stratified => PixelSampler::Stratified(Stratified {
+                seed: mix(seed, pixel, 0),
+                sample,
+                samples: samples.max(1),
+                dimension: 0,
+            }),
+            SamplerKind::Halton => PixelSampler::Halton(Halton {
+                seed: mix(seed, pixel, 0),
+                sample,
+                dimension: 0,
+            }),
+            SamplerKind::Sobol => PixelSampler::Sobol(Sobol {
+                seed: mix(seed, pixel, 0),
+                sample,
+                dimension: 0,
+            }),
+            SamplerKind::BlueNoise => PixelSampler::BlueNoise(BlueNoise {
+                seed,
+                x,
+                y,
+                sample,
+                dimension: 0,
+            }),
+        }
+    }
+}
+
+// Any of the samplers, so one can be made for every camera sample without
+// going through the heap.
+pub enum PixelSampler {
+    Independent(Independent),
+    Stratified(Stratified),
+    Halton(Halton),
+    Sobol(Sobol),
+    BlueNoise(BlueNoise),
+}
+
+impl Sampler for PixelSampler {
+    fn next_1d(&mut self) -> f32 {
+        match self {
+            PixelSampler::Independent(s) => s.next_1d(),
+            PixelSampler::Stratified(s) => s.next_1d(),
+            PixelSampler::Halton(s) => s.next_1d(),
+            PixelSampler::Sobol(s) => s.next_1d(),
+            PixelSampler::BlueNoise(s) => s.next_1d(),
+        }
+    }
+
+    fn next_2d(&mut self) -> Vec2 {
+        match self {
+            PixelSampler::Independent(s) => s.next_2d(),
+            PixelSampler::Stratified(s) => s.next_2d(),
+            PixelSampler::Halton(s) => s.next_2d(),
+            PixelSampler::Sobol(s) => s.next_2d(),
+            PixelSampler::BlueNoise(s) => s.next_2d(),
+        }
+    }
+}
+
+// Plain pseudo-random numbers, from a SplitMix64 generator seeded by the
+// render seed, the pixel and the sample index.
+pub struct Independent {
+    state: u64,
+}
+
+impl Independent {
+    pub fn new(seed: u64, pixel: u64, sample: u64) -> Self {
+        Self {
+            state: mix(seed, pixel, sample),
+        }
+    }
+}
+
+impl Sampler for Independent {
+    fn next_1d(&mut self) -> f32 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        to_unit(hash(self.state))
+    }
+}
+
+// Kensler's hashed permutation of [0, `length`): the element that `i` moves
+// to, under the shuffle picked by `seed`.
+fn permute(mut i: u32, length: u32, seed: u32) -> u32 {
+    let mut w = length - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    // Walks the cycle until it lands back inside the range.
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < length {
+            break;
+        }
+    }
+    i.wrapping_add(seed) % length
+}
+
+// Splits each dimension into as many strata as there are samples, or each
+// pair into a grid, and gives every sample a different one, jittered within
+// it. The strata are shuffled per pixel and dimension so that dimensions
+// don't line up with each other.
+pub struct Stratified {
+    seed: u64,
+    sample: u32,
+    samples: u32,
+    dimension: u64,
+}
+
+impl Stratified {
+    fn next_hash(&mut self) -> u64 {
+        self.dimension += 1;
+        hash(self.seed ^ hash(self.dimension))
+    }
+}
+
+impl Sampler for Stratified {
+    fn next_1d(&mut self) -> f32 {
+        let h = self.next_hash();
+        let stratum = permute(self.sample % self.samples, self.samples, h as u32);
+        let jitter = to_unit(hash(h ^ self.sample as u64));
+        ((stratum as f32 + jitter) / self.samples as f32).min(ONE_MINUS_EPSILON)
+    }
+
+    // Samples beyond the largest grid that fits go round the grid again.
+    fn next_2d(&mut self) -> Vec2 {
+        let h = self.next_hash();
+        let nx = (self.samples as f32).sqrt() as u32;
+        let ny = self.samples / nx;
+        let stratum = permute(self.sample % (nx * ny), nx * ny, h as u32);
+        let jitter = hash(h ^ self.sample as u64);
+        vec2(
+            (((stratum % nx) as f32 + to_unit(jitter)) / nx as f32).min(ONE_MINUS_EPSILON),
+            (((stratum / nx) as f32 + to_unit(hash(jitter))) / ny as f32).min(ONE_MINUS_EPSILON),
+        )
+    }
+}
+
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109,
+    113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193, 197, 199, 211, 223, 227, 229, 233, 239,
+    241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307, 311,
+];
+
+// Mirrors the digits of `i` in `base` about the point, passing each digit
+// through a permutation picked by `seed` and its place. Scrambling keeps the
+// points stratified, but breaks up the lines the larger bases otherwise
+// fall into at low sample counts. The zeros after the last digit are
+// scrambled too, down to the precision of a float.
+fn scrambled_radical_inverse(base: u32, mut i: u64, seed: u64) -> f32 {
+    let inv_base = 1.0 / base as f64;
+    let mut scale = inv_base;
+    let mut result = 0.0;
+    let mut place = 0;
+    while scale > 1e-8 {
+        let digit = (i % base as u64) as u32;
+        result += permute(digit, base, hash(seed ^ place) as u32) as f64 * scale;
+        i /= base as u64;
+        scale *= inv_base;
+        place += 1;
+    }
+    (result as f32).min(ONE_MINUS_EPSILON)
+}
+
+// The Halton sequence, a prime base per dimension, scrambled differently in
+// each pixel so neighbouring pixels don't repeat each other. Past the last
+// prime it falls back on plain random numbers.
+pub struct Halton {
+    seed: u64,
+    sample: u32,
+    dimension: usize,
+}
+
+impl Sampler for Halton {
+    fn next_1d(&mut self) -> f32 {
+        let d = self.dimension;
+        self.dimension += 1;
+        let seed = hash(self.seed ^ hash(d as u64));
+        match PRIMES.get(d) {
+            Some(&base) => scrambled_radical_inverse(base, self.sample as u64, seed),
+            None => to_unit(hash(seed ^ hash(self.sample as u64))),
+        }
+    }
+}
+
+// Laine and Karras' hash, which only lets each bit affect the bits above it.
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+// Owen scrambling: randomly flips each bit depending only on the bits above
+// it, which shuffles a sequence without losing its stratification.
+fn owen_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+// The first two Sobol dimensions, as fixed point fractions. The first is the
+// van der Corput sequence, and the second's generator matrix is Pascal's
+// triangle, so neither needs a table.
+fn sobol_2d(i: u32) -> (u32, u32) {
+    let mut y = 0;
+    let mut v = 1 << 31;
+    let mut bits = i;
+    while bits != 0 {
+        if bits & 1 != 0 {
+            y ^= v;
+        }
+        bits >>= 1;
+        v ^= v >> 1;
+    }
+    (i.reverse_bits(), y)
+}
+
+fn to_float(x: u32) -> f32 {
+    (x >> 8) as f32 / (1u32 << 24) as f32
+}
+
+// Burley's Owen-scrambled Sobol points. Every call gets the first two Sobol
+// dimensions again, with the order of the samples shuffled and the values
+// scrambled by a seed of its own, so there are as many well stratified
+// dimensions as anyone asks for.
+fn scrambled_sobol(sample: u32, seed: u64) -> (f32, f32) {
+    let index = owen_scramble(sample, seed as u32);
+    let (x, y) = sobol_2d(index);
+    let (sx, sy) = ((seed >> 32) as u32, hash(seed) as u32);
+    (to_float(owen_scramble(x, sx)), to_float(owen_scramble(y, sy)))
+}
+
+pub struct Sobol {
+    seed: u64,
+    sample: u32,
+    dimension: u64,
+}
+
+impl Sobol {
+    fn next(&mut self) -> (f32, f32) {
+        self.dimension += 1;
+        scrambled_sobol(self.sample, hash(self.seed ^ hash(self.dimension)))
+    }
+}
+
+impl Sampler for Sobol {
+    fn next_1d(&mut self) -> f32 {
+        self.next().0
+    }
+
+    fn next_2d(&mut self) -> Vec2 {
+        let (x, y) = self.next();
+        vec2(x, y)
+    }
+}
+
+// Scrambled Sobol points shared by every pixel, each pixel shifting them by
+// a value from a blue noise mask. Neighbouring pixels get very different
+// shifts, so what error is left looks like fine grain rather than blotches.
+pub struct BlueNoise {
+    seed: u64,
+    x: u32,
+    y: u32,
+    sample: u32,
+    dimension: u64,
+}
+
+impl BlueNoise {
+    // A different toroidal shift of the mask for each dimension and axis.
+    fn offset(&self, h: u64) -> f32 {
+        let size = MASK_SIZE as u64;
+        let (dx, dy) = (h % size, (h >> 32) % size);
+        let x = (self.x as u64 + dx) % size;
+        let y = (self.y as u64 + dy) % size;
+        blue_noise_mask()[(y * size + x) as usize]
+    }
+
+    fn next(&mut self) -> (f32, f32) {
+        self.dimension += 1;
+        let h = hash(self.seed ^ hash(self.dimension));
+        let (x, y) = scrambled_sobol(self.sample, h);
+        (rotate(x, self.offset(hash(h ^ 1))), rotate(y, self.offset(hash(h ^ 2))))
+    }
+}
+
+impl Sampler for BlueNoise {
+    fn next_1d(&mut self) -> f32 {
+        self.next().0
+    }
+
+    fn next_2d(&mut self) -> Vec2 {
+        let (x, y) = self.next();
+        vec2(x, y)
+    }
+}
+
+const MASK_SIZE: usize = 64;
+
+// Built the first time it's needed, and the same every time.
+fn blue_noise_mask() -> &'static [f32] {
+    static MASK: OnceLock<Vec<f32>> = OnceLock::new();
+    MASK.get_or_init(|| void_and_cluster(MASK_SIZE))
+}
+
+// Ulichney's void and cluster method, ranking every cell of a tiling square
+// so that the cells below any threshold are evenly spread. Returns the ranks
+// as values in (0, 1).
+fn void_and_cluster(size: usize) -> Vec<f32> {
+    let n = size * size;
+    // Gaussian falloff for every offset, wrapping around the edges.
+    let sigma = 1.5f32;
+    let mut kernel = vec![0.0f32; n];
+    for dy in 0..size {
+        for dx in 0..size {
+            let (wx, wy) = (dx.min(size - dx) as f32, dy.min(size - dy) as f32);
+            kernel[dy * size + dx] = (-(wx * wx + wy * wy) / (2.0 * sigma * sigma)).exp();
+        }
+    }
+    let update = |energy: &mut [f32], p: usize, sign: f32| {
+        let (px, py) = (p % size, p / size);
+        for (q, e) in energy.iter_mut().enumerate() {
+            let dx = (q % size + size - px) % size;
+            let dy = (q / size + size - py) % size;
+            *e += sign * kernel[dy * size + dx];
+        }
+    };
+    // The most crowded point, or the emptiest gap.
+    let tightest = |pattern: &[bool], energy: &[f32]| {
+        (0..n).filter(|&p| pattern[p]).max_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap()
+    };
+    let largest_void = |pattern: &[bool], energy: &[f32]| {
+        (0..n).filter(|&p| !pattern[p]).min_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap()
+    };
+
+    // Start from a tenth of the cells at random, then move points from
+    // clusters into voids until they're evenly spread.
+    let mut pattern = vec![false; n];
+    let mut energy = vec![0.0; n];
+    let mut initial = 0;
+    for (i, p) in pattern.iter_mut().enumerate() {
+        if to_unit(hash(i as u64 + 1)) < 0.1 {
+            *p = true;
+            update(&mut energy, i, 1.0);
+            initial += 1;
+        }
+    }
+    loop {
+        let cluster = tightest(&pattern, &energy);
+        pattern[cluster] = false;
+        update(&mut energy, cluster, -1.0);
+        let void = largest_void(&pattern, &energy);
+        pattern[void] = true;
+        update(&mut energy, void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+
+    // The starting points are ranked by taking them away, clusters first,
+    // and the rest by filling in the voids.
+    let mut ranks = vec![0; n];
+    let (mut removing, mut removing_energy) = (pattern.clone(), energy.clone());
+    for rank in (0..initial).rev() {
+        let cluster = tightest(&removing, &removing_energy);
+        removing[cluster] = false;
+        update(&mut removing_energy, cluster, -1.0);
+        ranks[cluster] = rank;
+    }
+    for rank in initial..n {
+        let void = largest_void(&pattern, &energy);
+        pattern[void] = true;
+        update(&mut energy, void, 1.0);
+        ranks[void] = rank;
+    }
+    ranks.iter().map(|&r| (r as f32 + 0.5) / n as f32).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [SamplerKind; 5] = [
+        SamplerKind::Independent,
+        SamplerKind::Stratified,
+        SamplerKind::Halton,
+        SamplerKind::Sobol,
+        SamplerKind::BlueNoise,
+    ];
+
+    // The pair each of the first `samples` samples of one pixel gets, after
+    // skipping `skip` pairs.
+    fn dimension(kind: SamplerKind, x: u32, y: u32, samples: u32, skip: usize) -> Vec<Vec2> {
+        (0..samples)
+            .map(|sample| {
+                let mut sampler = kind.sampler(3, x, y, sample, samples);
+                for _ in 0..skip {
+                    sampler.next_2d();
+                }
+                sampler.next_2d()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn values_in_unit_interval() {
+        for kind in KINDS {
+            for sample in 0..64 {
+                let mut sampler = kind.sampler(1, sample % 7, sample / 7, sample, 64);
+                for _ in 0..80 {
+                    let x = sampler.next_1d();
+                    assert!((0.0..1.0).contains(&x), "{:?} gave {}", kind, x);
+                    let p = sampler.next_2d();
+                    assert!((0.0..1.0).contains(&p.x) && (0.0..1.0).contains(&p.y), "{:?} gave {}", kind, p);
+                }
+            }
+        }
+    }
+
+    // One point in each of `nx` by `ny` equal cells.
+    fn one_per_cell(points: &[Vec2], nx: usize, ny: usize) -> bool {
+        let mut seen = vec![false; nx * ny];
+        for p in points {
+            let cell = (p.y * ny as f32) as usize * nx + (p.x * nx as f32) as usize;
+            if seen[cell] {
+                return false;
+            }
+            seen[cell] = true;
+        }
+        seen.iter().all(|&s| s)
+    }
+
+    #[test]
+    fn stratified_fills_every_stratum() {
+        for skip in 0..4 {
+            let points = dimension(SamplerKind::Stratified, 5, 9, 16, skip);
+            assert!(one_per_cell(&points, 4, 4));
+            let singles: Vec<f32> = (0..16)
+                .map(|sample| {
+                    let mut sampler = SamplerKind::Stratified.sampler(3, 5, 9, sample, 16);
+                    for _ in 0..skip {
+                        sampler.next_1d();
+                    }
+                    sampler.next_1d()
+                })
+                .collect();
+            let points: Vec<Vec2> = singles.iter().map(|&x| vec2(x, 0.0)).collect();
+            assert!(one_per_cell(&points, 16, 1));
+        }
+    }
+
+    // Any power of two samples form a (0, 2)-net: every way of cutting the
+    // square into that many equal boxes of power of two sides puts one point
+    // in each.
+    #[test]
+    fn sobol_is_a_net() {
+        for k in 0..=8 {
+            for skip in 0..4 {
+                let points = dimension(SamplerKind::Sobol, 2, 11, 1 << k, skip);
+                for a in 0..=k {
+                    assert!(one_per_cell(&points, 1 << a, 1 << (k - a)), "2^{} samples, {} by {}", k, 1 << a, 1 << (k - a));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn permute_is_a_bijection() {
+        for length in 1..200 {
+            for seed in [0, 1, 0xdeadbeef, 0x12345678] {
+                let mut seen = vec![false; length as usize];
+                for i in 0..length {
+                    let j = permute(i, length, seed) as usize;
+                    assert!(!seen[j], "{} twice for length {} and seed {}", j, length, seed);
+                    seen[j] = true;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn void_and_cluster_ranks_every_cell_once() {
+        let size = 16;
+        let mut ranks: Vec<usize> = void_and_cluster(size).iter().map(|r| (r * (size * size) as f32) as usize).collect();
+        ranks.sort();
+        assert_eq!(ranks, (0..size * size).collect::<Vec<_>>());
+    }
+}
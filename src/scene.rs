@@ -0,0 +1,711 @@
+use crate::aov;
+use crate::camera::{self, Camera, Equirectangular, Fisheye, Orthographic, Perspective, Projection, Shutter};
+use crate::hitable::{Hitable, HitableList};
+use crate::material::{Dielectric, DiffuseLight, Isotropic, Lambertian, Material, Metal, TaggedMaterial};
+use crate::medium::ConstantMedium;
+use crate::mesh::Mesh;
+use crate::noise::{Marble, NoiseTexture, Perlin, Wood, Worley};
+use crate::obj;
+use crate::renderer::Background;
+use crate::sphere::{MovingSphere, Sphere};
+use crate::texture::{Checker, ImageTexture, SolidColour, Texture, WrapMode};
+use crate::transform::{self, Animated, Instance, Keyframe};
+use crate::triangle::Triangle;
+use anyhow::{anyhow, Context, Error, Result};
+use glm::{vec3, Mat4, Quat, Vec3};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::f32::consts::PI;
+use std::path::Path;
+use std::sync::Arc;
+use toml::Spanned;
+
+// A scene file is TOML, along the lines of:
+//
+//     background = "sky"   # or an [r, g, b] colour
+//
+//     [camera]
+//     from = [-2.0, 2.0, 1.0]
+//     at = [0.0, 0.0, -1.0]
+//     up = [0.0, 1.0, 0.0]
+//     vfov = 20.0
+//     aperture = 0.1       # lens diameter, zero for a pinhole
+//     focus_dist = 3.4     # or autofocus = true, defaulting to the distance to `at`
+//     projection = "perspective"
+//     shutter = [0.0, 1.0] # open and close times, for motion blur
+//
+//     [textures.tiles]
+//     type = "checker"
+//     even = [0.2, 0.3, 0.1]
+//     odd = [0.9, 0.9, 0.9]
+//     scale = 0.5
+//
+//     [materials.ground]
+//     type = "lambertian"
+//     albedo = "tiles"   # or an [r, g, b] colour
+//
+//     [[objects]]
+//     type = "sphere"
+//     centre = [0.0, -100.5, -1.0]
+//     radius = 100.0
+//     material = "ground"
+//
+// The camera's projection is perspective, orthographic (height, defaulting to
+// what vfov sees at `at`), fisheye (fov, defaulting to 180) or
+// equirectangular. Lens settings only apply to perspective cameras.
+//
+// Textures are solid (colour), checker (even, odd, scale), image (path, wrap
+// of "repeat", "clamp" or "mirror"), noise (colour, scale, octaves), marble
+// and wood (low, high, scale, turbulence, octaves) and worley (low, high,
+// scale), where colours can also name another texture. Noise takes a seed,
+// defaulting to zero. Materials are lambertian (albedo), metal (albedo,
+// fuzz), dielectric (refractive_index), diffuse_light (emit) and isotropic
+// (albedo, for media). Objects are sphere (centre, radius), moving_sphere
+// (centre0 at time0, centre1 at time1, radius), triangle (vertices), quad (q,
+// u, v), mesh (path to an OBJ file, relative to the scene, which brings its
+// own materials) and constant_medium (density, with a closed object as its
+// boundary, such as `boundary = { type = "sphere", ... }`).
+//
+// Any object can be moved with a translate, rotate (degrees about x, y then
+// z) and scale, applied in the opposite order. To place the same geometry
+// several times without copying it, describe it once under [shapes.<name>]
+// and refer to it from objects of type instance (shape, plus the same three
+// transforms).
+//
+// Objects can also be animated with a list of keyframes, each a time along
+// with an optional translate, rotate and scale:
+//
+//     keyframes = [
+//         { time = 0.0 },
+//         { time = 1.0, translate = [0.0, 0.5, 0.0], rotate = [0.0, 45.0, 0.0] },
+//     ]
+pub struct Scene {
+    pub camera: Box<dyn Camera + Sync + Send>,
+    pub world: HitableList,
+    pub background: Background,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneDesc {
+    camera: CameraDesc,
+    background: Option<BackgroundDesc>,
+    #[serde(default)]
+    textures: BTreeMap<String, TextureDesc>,
+    #[serde(default)]
+    materials: BTreeMap<String, MaterialDesc>,
+    #[serde(default)]
+    shapes: BTreeMap<String, ObjectDesc>,
+    #[serde(default)]
+    objects: Vec<ObjectDesc>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BackgroundDesc {
+    Named(String),
+    Colour([f32; 3]),
+}
+
+// Either a constant colour or the name of a texture.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ColourDesc {
+    Colour([f32; 3]),
+    Texture(String),
+}
+
+static WHITE: ColourDesc = ColourDesc::Colour([1.0, 1.0, 1.0]);
+static BLACK: ColourDesc = ColourDesc::Colour([0.0, 0.0, 0.0]);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TextureDesc {
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    colour: Option<ColourDesc>,
+    even: Option<ColourDesc>,
+    odd: Option<ColourDesc>,
+    low: Option<ColourDesc>,
+    high: Option<ColourDesc>,
+    scale: Option<Spanned<f32>>,
+    path: Option<String>,
+    wrap: Option<Spanned<String>>,
+    seed: Option<u64>,
+    octaves: Option<u32>,
+    turbulence: Option<f32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    from: [f32; 3],
+    at: [f32; 3],
+    up: Option<[f32; 3]>,
+    vfov: Option<Spanned<f32>>,
+    aperture: Option<Spanned<f32>>,
+    focus_dist: Option<Spanned<f32>>,
+    autofocus: Option<bool>,
+    projection: Option<Spanned<String>>,
+    height: Option<Spanned<f32>>,
+    fov: Option<Spanned<f32>>,
+    shutter: Option<Spanned<[f32; 2]>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaterialDesc {
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    albedo: Option<ColourDesc>,
+    fuzz: Option<Spanned<f32>>,
+    refractive_index: Option<Spanned<f32>>,
+    emit: Option<[f32; 3]>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ObjectDesc {
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    material: Option<Spanned<String>>,
+    centre: Option<[f32; 3]>,
+    radius: Option<Spanned<f32>>,
+    vertices: Option<[[f32; 3]; 3]>,
+    q: Option<[f32; 3]>,
+    u: Option<[f32; 3]>,
+    v: Option<[f32; 3]>,
+    path: Option<String>,
+    centre0: Option<[f32; 3]>,
+    centre1: Option<[f32; 3]>,
+    time0: Option<f32>,
+    time1: Option<f32>,
+    keyframes: Option<Vec<KeyframeDesc>>,
+    shape: Option<Spanned<String>>,
+    boundary: Option<Box<ObjectDesc>>,
+    density: Option<Spanned<f32>>,
+    translate: Option<[f32; 3]>,
+    rotate: Option<[f32; 3]>,
+    scale: Option<Spanned<[f32; 3]>>,
+}
+
+// Degrees about x, then y, then z.
+fn euler_rotation(rotate: Option<[f32; 3]>) -> Quat {
+    let r = rotate.map_or(vec3(0.0, 0.0, 0.0), to_vec3) * (PI / 180.0);
+    glm::quat_angle_axis(r.z, &vec3(0.0, 0.0, 1.0))
+        * glm::quat_angle_axis(r.y, &vec3(0.0, 1.0, 0.0))
+        * glm::quat_angle_axis(r.x, &vec3(1.0, 0.0, 0.0))
+}
+
+fn transform_matrix(source: &Source, o: &ObjectDesc) -> Result<Mat4> {
+    let scale = match &o.scale {
+        Some(scale) => source.scale(scale)?,
+        None => vec3(1.0, 1.0, 1.0),
+    };
+    Ok(transform::compose(
+        &o.translate.map_or(vec3(0.0, 0.0, 0.0), to_vec3),
+        &euler_rotation(o.rotate),
+        &scale,
+    ))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyframeDesc {
+    time: f32,
+    translate: Option<[f32; 3]>,
+    rotate: Option<[f32; 3]>,
+    scale: Option<Spanned<[f32; 3]>>,
+}
+
+impl KeyframeDesc {
+    fn to_keyframe(&self, source: &Source) -> Result<Keyframe> {
+        Ok(Keyframe {
+            time: self.time,
+            translation: self.translate.map_or(vec3(0.0, 0.0, 0.0), to_vec3),
+            rotation: euler_rotation(self.rotate),
+            scale: match &self.scale {
+                Some(scale) => source.scale(scale)?,
+                None => vec3(1.0, 1.0, 1.0),
+            },
+        })
+    }
+}
+
+// Turns byte offsets from the parser into file:line errors.
+struct Source<'a> {
+    path: &'a Path,
+    text: &'a str,
+}
+
+impl Source<'_> {
+    fn error_at<T>(&self, span: &Spanned<T>, message: String) -> Error {
+        let line = self.text[..span.start()].matches('\n').count() + 1;
+        anyhow!("{}:{}: {}", self.path.display(), line, message)
+    }
+
+    // Fetches a field a given type of material or object can't do without.
+    fn require<T>(&self, field: Option<T>, kind: &Spanned<String>, name: &str) -> Result<T> {
+        field.ok_or_else(|| self.error_at(kind, format!("{} is missing '{}'", kind.get_ref(), name)))
+    }
+
+    fn positive(&self, value: &Spanned<f32>, name: &str) -> Result<f32> {
+        if *value.get_ref() > 0.0 {
+            Ok(*value.get_ref())
+        } else {
+            Err(self.error_at(value, format!("'{}' must be positive, got {}", name, value.get_ref())))
+        }
+    }
+
+    // Flattening something completely would leave no way back to it.
+    fn scale(&self, value: &Spanned<[f32; 3]>) -> Result<Vec3> {
+        if value.get_ref().iter().all(|s| s.is_finite() && *s != 0.0) {
+            Ok(to_vec3(*value.get_ref()))
+        } else {
+            Err(self.error_at(value, format!("'scale' can't be zero in any direction, got {:?}", value.get_ref())))
+        }
+    }
+}
+
+fn to_vec3(a: [f32; 3]) -> Vec3 {
+    vec3(a[0], a[1], a[2])
+}
+
+pub fn load(path: &Path, aspect_ratio: f32, projection: Option<Projection>) -> Result<Scene> {
+    let text = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    parse(&text, path, aspect_ratio, projection)
+}
+
+// `path` is used for error messages and to find files the scene refers to.
+// `projection`, if given, overrides the one in the scene.
+pub fn parse(text: &str, path: &Path, aspect_ratio: f32, projection: Option<Projection>) -> Result<Scene> {
+    let source = Source { path, text };
+    let desc: SceneDesc = toml::from_str(text).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+
+    let background = match desc.background {
+        None => Background::Sky,
+        Some(BackgroundDesc::Named(name)) if name == "sky" => Background::Sky,
+        Some(BackgroundDesc::Named(name)) => {
+            return Err(anyhow!("{}: unknown background '{}', expected \"sky\" or a colour", path.display(), name))
+        }
+        Some(BackgroundDesc::Colour(c)) => Background::Colour(to_vec3(c)),
+    };
+
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut textures = Textures {
+        source: &source,
+        dir,
+        descs: &desc.textures,
+        built: HashMap::new(),
+        building: Vec::new(),
+    };
+    let mut materials = HashMap::new();
+    for (name, m) in desc.materials.iter() {
+        let material = TaggedMaterial {
+            material: build_material(&mut textures, m)?,
+            id: aov::id_from_name(name),
+        };
+        materials.insert(name.as_str(), Arc::new(material) as Arc<dyn Material + Sync + Send>);
+    }
+
+    let mut objects = Objects {
+        source: &source,
+        dir,
+        materials,
+        shapes: HashMap::new(),
+    };
+    for (name, o) in desc.shapes.iter() {
+        if o.kind.get_ref() == "instance" {
+            return Err(source.error_at(&o.kind, "shapes can't be instances themselves".to_string()));
+        }
+        let shape = objects.build(o, None)?;
+        objects.shapes.insert(name.as_str(), Arc::from(shape));
+    }
+    let list = desc.objects.iter().map(|o| objects.build(o, None)).collect::<Result<Vec<_>>>()?;
+
+    let world = HitableList { list };
+
+    let camera: Box<dyn Camera + Sync + Send> = {
+        let c = &desc.camera;
+        let projection = match (projection, &c.projection) {
+            (Some(p), _) => p,
+            (None, Some(p)) => p.get_ref().parse().map_err(|e| source.error_at(p, format!("{}", e)))?,
+            (None, None) => Projection::Perspective,
+        };
+        let vfov = match &c.vfov {
+            Some(vfov) => source.positive(vfov, "vfov")?,
+            None => 40.0,
+        };
+        let (from, at) = (to_vec3(c.from), to_vec3(c.at));
+        let up = c.up.map_or(vec3(0.0, 1.0, 0.0), to_vec3);
+        let camera: Box<dyn Camera + Sync + Send> = match projection {
+            Projection::Perspective => {
+                let aperture = match &c.aperture {
+                    Some(a) if *a.get_ref() < 0.0 => {
+                        return Err(source.error_at(a, format!("'aperture' can't be negative, got {}", a.get_ref())))
+                    }
+                    Some(a) => *a.get_ref(),
+                    None => 0.0,
+                };
+                let focus_dist = match (&c.focus_dist, c.autofocus.unwrap_or(false)) {
+                    (Some(f), true) => {
+                        return Err(source.error_at(f, "'focus_dist' can't be combined with autofocus".to_string()))
+                    }
+                    (Some(f), false) => source.positive(f, "focus_dist")?,
+                    (None, true) => camera::autofocus(&from, &at, &world).unwrap_or_else(|| {
+                        warn!("{}: nothing in the centre of the frame to autofocus on", path.display());
+                        glm::distance(&from, &at)
+                    }),
+                    (None, false) => glm::distance(&from, &at),
+                };
+                Box::new(Perspective::new(from, at, up, vfov, aspect_ratio, aperture, focus_dist))
+            }
+            Projection::Orthographic => {
+                let height = match &c.height {
+                    Some(h) => source.positive(h, "height")?,
+                    None => camera::orthographic_height(vfov, glm::distance(&from, &at)),
+                };
+                Box::new(Orthographic::new(from, at, up, height, aspect_ratio))
+            }
+            Projection::Fisheye => {
+                let fov = match &c.fov {
+                    Some(fov) => source.positive(fov, "fov")?,
+                    None => 180.0,
+                };
+                Box::new(Fisheye::new(from, at, up, fov, aspect_ratio))
+            }
+            Projection::Equirectangular => Box::new(Equirectangular::new(from, at, up)),
+        };
+        match &c.shutter {
+            Some(s) if s.get_ref()[1] < s.get_ref()[0] => {
+                return Err(source.error_at(s, "the shutter can't close before it opens".to_string()))
+            }
+            Some(s) => {
+                let [open, close] = *s.get_ref();
+                Box::new(Shutter { camera, open, close })
+            }
+            None => camera,
+        }
+    };
+
+    Ok(Scene {
+        camera,
+        world,
+        background,
+    })
+}
+
+// Builds objects, along with the shapes they can instance.
+struct Objects<'a> {
+    source: &'a Source<'a>,
+    dir: &'a Path,
+    materials: HashMap<&'a str, Arc<dyn Material + Sync + Send>>,
+    shapes: HashMap<&'a str, Arc<dyn Hitable + Sync + Send>>,
+}
+
+impl Objects<'_> {
+    // `default_material` stands in when the object doesn't name one.
+    fn build(
+        &self,
+        o: &ObjectDesc,
+        default_material: Option<&Spanned<String>>,
+    ) -> Result<Box<dyn Hitable + Sync + Send>> {
+        let kind = &o.kind;
+        let material = || -> Result<Arc<dyn Material + Sync + Send>> {
+            let name = self.source.require(o.material.as_ref().or(default_material), kind, "material")?;
+            self.materials
+                .get(name.get_ref().as_str())
+                .cloned()
+                .ok_or_else(|| self.source.error_at(name, format!("unknown material '{}'", name.get_ref())))
+        };
+        let object: Box<dyn Hitable + Sync + Send> = match kind.get_ref().as_str() {
+            "sphere" => Box::new(Sphere {
+                centre: to_vec3(self.source.require(o.centre, kind, "centre")?),
+                radius: self.source.positive(self.source.require(o.radius.as_ref(), kind, "radius")?, "radius")?,
+                material: material()?,
+            }),
+            "triangle" => {
+                let [a, b, c] = self.source.require(o.vertices, kind, "vertices")?;
+                Box::new(Triangle {
+                    vertices: [to_vec3(a), to_vec3(b), to_vec3(c)],
+                    material: material()?,
+                })
+            }
+            "quad" => Box::new(Mesh::quad(
+                to_vec3(self.source.require(o.q, kind, "q")?),
+                to_vec3(self.source.require(o.u, kind, "u")?),
+                to_vec3(self.source.require(o.v, kind, "v")?),
+                material()?,
+            )),
+            "mesh" => {
+                if let Some(m) = &o.material {
+                    return Err(self.source.error_at(m, "meshes take their materials from their MTL files".to_string()));
+                }
+                let mesh_path = self.dir.join(self.source.require(o.path.as_ref(), kind, "path")?);
+                Box::new(obj::load(&mesh_path).map_err(|e| self.source.error_at(kind, format!("{:#}", e)))?)
+            }
+            "moving_sphere" => Box::new(MovingSphere {
+                centre0: to_vec3(self.source.require(o.centre0, kind, "centre0")?),
+                centre1: to_vec3(self.source.require(o.centre1, kind, "centre1")?),
+                time0: o.time0.unwrap_or(0.0),
+                time1: o.time1.unwrap_or(1.0),
+                radius: self.source.positive(self.source.require(o.radius.as_ref(), kind, "radius")?, "radius")?,
+                material: material()?,
+            }),
+            "instance" => {
+                let name = self.source.require(o.shape.as_ref(), kind, "shape")?;
+                let shape = self
+                    .shapes
+                    .get(name.get_ref().as_str())
+                    .ok_or_else(|| self.source.error_at(name, format!("unknown shape '{}'", name.get_ref())))?;
+                Box::new(Instance::new(shape.clone(), transform_matrix(self.source, o)?))
+            }
+            "constant_medium" => {
+                // The boundary only gives the volume its shape, so it can
+                // borrow the medium's material rather than needing its own.
+                let boundary = self.source.require(o.boundary.as_ref(), kind, "boundary")?;
+                Box::new(ConstantMedium {
+                    boundary: self.build(boundary, o.material.as_ref())?,
+                    density: self.source.positive(self.source.require(o.density.as_ref(), kind, "density")?, "density")?,
+                    phase: material()?,
+                })
+            }
+            other => return Err(self.source.error_at(kind, format!("unknown object type '{}'", other))),
+        };
+        // Anything else that's been moved gets an instance of its own.
+        let object = if kind.get_ref() != "instance" && (o.translate.is_some() || o.rotate.is_some() || o.scale.is_some()) {
+            Box::new(Instance::new(Arc::from(object), transform_matrix(self.source, o)?))
+        } else {
+            object
+        };
+        let object = match &o.keyframes {
+            Some(keyframes) if keyframes.is_empty() => {
+                return Err(self.source.error_at(kind, "keyframes can't be empty".to_string()))
+            }
+            Some(keyframes) => {
+                let mut keyframes = keyframes.iter().map(|k| k.to_keyframe(self.source)).collect::<Result<Vec<_>>>()?;
+                keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+                let mut lights = Vec::new();
+                object.lights(&mut lights);
+                if !lights.is_empty() {
+                    warn!(
+                        "{}: animated lights aren't sampled directly, so they'll only be found by chance",
+                        self.source.path.display()
+                    );
+                }
+                Box::new(Animated { object, keyframes })
+            }
+            None => object,
+        };
+        Ok(object)
+    }
+}
+
+// Builds textures on first use, so they can refer to each other in any order.
+struct Textures<'a> {
+    source: &'a Source<'a>,
+    dir: &'a Path,
+    descs: &'a BTreeMap<String, TextureDesc>,
+    built: HashMap<&'a str, Arc<dyn Texture + Sync + Send>>,
+    building: Vec<&'a str>,
+}
+
+impl<'a> Textures<'a> {
+    // `at` is where to report errors, as the colour itself has no position.
+    fn colour(&mut self, c: &'a ColourDesc, at: &Spanned<String>) -> Result<Arc<dyn Texture + Sync + Send>> {
+        match c {
+            ColourDesc::Colour(c) => Ok(Arc::new(SolidColour { colour: to_vec3(*c) })),
+            ColourDesc::Texture(name) => self.get(name, at),
+        }
+    }
+
+    fn get(&mut self, name: &'a str, at: &Spanned<String>) -> Result<Arc<dyn Texture + Sync + Send>> {
+        if let Some(t) = self.built.get(name) {
+            return Ok(t.clone());
+        }
+        let desc = match self.descs.get(name) {
+            Some(desc) => desc,
+            None => return Err(self.source.error_at(at, format!("unknown texture '{}'", name))),
+        };
+        if self.building.contains(&name) {
+            return Err(self.source.error_at(&desc.kind, format!("texture '{}' refers to itself", name)));
+        }
+        self.building.push(name);
+        let texture = self.build(desc)?;
+        self.building.pop();
+        self.built.insert(name, texture.clone());
+        Ok(texture)
+    }
+
+    fn build(&mut self, t: &'a TextureDesc) -> Result<Arc<dyn Texture + Sync + Send>> {
+        let source = self.source;
+        let kind = &t.kind;
+        let scale = match &t.scale {
+            Some(scale) => source.positive(scale, "scale")?,
+            None => 1.0,
+        };
+        let seed = t.seed.unwrap_or(0);
+        let texture: Arc<dyn Texture + Sync + Send> = match kind.get_ref().as_str() {
+            "solid" => self.colour(source.require(t.colour.as_ref(), kind, "colour")?, kind)?,
+            "checker" => Arc::new(Checker {
+                even: self.colour(source.require(t.even.as_ref(), kind, "even")?, kind)?,
+                odd: self.colour(source.require(t.odd.as_ref(), kind, "odd")?, kind)?,
+                scale,
+            }),
+            "noise" => Arc::new(NoiseTexture {
+                noise: Perlin::new(seed),
+                colour: self.colour(t.colour.as_ref().unwrap_or(&WHITE), kind)?,
+                scale,
+                octaves: t.octaves.unwrap_or(1),
+            }),
+            "marble" => Arc::new(Marble {
+                noise: Perlin::new(seed),
+                low: self.colour(t.low.as_ref().unwrap_or(&BLACK), kind)?,
+                high: self.colour(t.high.as_ref().unwrap_or(&WHITE), kind)?,
+                scale,
+                turbulence: t.turbulence.unwrap_or(10.0),
+                octaves: t.octaves.unwrap_or(7),
+            }),
+            "wood" => Arc::new(Wood {
+                noise: Perlin::new(seed),
+                low: self.colour(t.low.as_ref().unwrap_or(&BLACK), kind)?,
+                high: self.colour(t.high.as_ref().unwrap_or(&WHITE), kind)?,
+                scale,
+                turbulence: t.turbulence.unwrap_or(1.0),
+                octaves: t.octaves.unwrap_or(4),
+            }),
+            "worley" => Arc::new(Worley {
+                seed,
+                low: self.colour(t.low.as_ref().unwrap_or(&BLACK), kind)?,
+                high: self.colour(t.high.as_ref().unwrap_or(&WHITE), kind)?,
+                scale,
+            }),
+            "image" => {
+                let wrap = match &t.wrap {
+                    None => WrapMode::Repeat,
+                    Some(w) => match w.get_ref().as_str() {
+                        "repeat" => WrapMode::Repeat,
+                        "clamp" => WrapMode::Clamp,
+                        "mirror" => WrapMode::Mirror,
+                        other => return Err(source.error_at(w, format!("unknown wrap mode '{}'", other))),
+                    },
+                };
+                let path = self.dir.join(source.require(t.path.as_ref(), kind, "path")?);
+                Arc::new(ImageTexture::load(&path, wrap).map_err(|e| source.error_at(kind, format!("{:#}", e)))?)
+            }
+            other => return Err(source.error_at(kind, format!("unknown texture type '{}'", other))),
+        };
+        Ok(texture)
+    }
+}
+
+fn build_material<'a>(textures: &mut Textures<'a>, m: &'a MaterialDesc) -> Result<Arc<dyn Material + Sync + Send>> {
+    let source = textures.source;
+    let kind = &m.kind;
+    let material: Arc<dyn Material + Sync + Send> = match kind.get_ref().as_str() {
+        "lambertian" => Arc::new(Lambertian {
+            albedo: textures.colour(source.require(m.albedo.as_ref(), kind, "albedo")?, kind)?,
+        }),
+        "metal" => {
+            let fuzz = match &m.fuzz {
+                Some(f) if !(0.0..=1.0).contains(f.get_ref()) => {
+                    return Err(source.error_at(f, format!("'fuzz' must be between 0 and 1, got {}", f.get_ref())))
+                }
+                Some(f) => *f.get_ref(),
+                None => 0.0,
+            };
+            Arc::new(Metal {
+                albedo: textures.colour(source.require(m.albedo.as_ref(), kind, "albedo")?, kind)?,
+                fuzz,
+            })
+        }
+        "dielectric" => Arc::new(Dielectric {
+            refractive_index: source.positive(
+                source.require(m.refractive_index.as_ref(), kind, "refractive_index")?,
+                "refractive_index",
+            )?,
+        }),
+        "isotropic" => Arc::new(Isotropic {
+            albedo: textures.colour(source.require(m.albedo.as_ref(), kind, "albedo")?, kind)?,
+        }),
+        "diffuse_light" => Arc::new(DiffuseLight {
+            emit: to_vec3(source.require(m.emit, kind, "emit")?),
+        }),
+        other => return Err(source.error_at(kind, format!("unknown material type '{}'", other))),
+    };
+    Ok(material)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The error from parsing `text`, which should fail.
+    fn error(text: &str) -> String {
+        match parse(text, Path::new("test.toml"), 1.0, None) {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => format!("{:#}", e),
+        }
+    }
+
+    const CAMERA: &str = "[camera]\nfrom = [0.0, 0.0, 1.0]\nat = [0.0, 0.0, 0.0]\n";
+
+    #[test]
+    fn unknown_material() {
+        let text = format!(
+            "{}\n[[objects]]\ntype = \"sphere\"\ncentre = [0.0, 0.0, 0.0]\nradius = 1.0\nmaterial = \"nothing\"\n",
+            CAMERA
+        );
+        assert_eq!(error(&text), "test.toml:9: unknown material 'nothing'");
+    }
+
+    #[test]
+    fn negative_scale() {
+        let text = format!(
+            "{}\n[textures.tiles]\ntype = \"checker\"\neven = [0.0, 0.0, 0.0]\nodd = [1.0, 1.0, 1.0]\nscale = -0.5\n\n\
+             [materials.floor]\ntype = \"lambertian\"\nalbedo = \"tiles\"\n\n\
+             [[objects]]\ntype = \"sphere\"\ncentre = [0.0, 0.0, 0.0]\nradius = 1.0\nmaterial = \"floor\"\n",
+            CAMERA
+        );
+        assert_eq!(error(&text), "test.toml:9: 'scale' must be positive, got -0.5");
+    }
+
+    #[test]
+    fn zero_object_scale() {
+        let text = format!(
+            "{}\n[materials.white]\ntype = \"lambertian\"\nalbedo = [1.0, 1.0, 1.0]\n\n\
+             [[objects]]\ntype = \"sphere\"\ncentre = [0.0, 0.0, 0.0]\nradius = 1.0\nmaterial = \"white\"\n\
+             scale = [1.0, 0.0, 1.0]\n",
+            CAMERA
+        );
+        assert_eq!(error(&text), "test.toml:14: 'scale' can't be zero in any direction, got [1.0, 0.0, 1.0]");
+    }
+
+    #[test]
+    fn no_keyframes() {
+        let text = format!(
+            "{}\n[materials.white]\ntype = \"lambertian\"\nalbedo = [1.0, 1.0, 1.0]\n\n\
+             [[objects]]\ntype = \"sphere\"\ncentre = [0.0, 0.0, 0.0]\nradius = 1.0\nmaterial = \"white\"\n\
+             keyframes = []\n",
+            CAMERA
+        );
+        assert_eq!(error(&text), "test.toml:10: keyframes can't be empty");
+    }
+
+    #[test]
+    fn shutter_closes_before_it_opens() {
+        let text = format!("{}shutter = [1.0, 0.5]\n", CAMERA);
+        assert_eq!(error(&text), "test.toml:4: the shutter can't close before it opens");
+    }
+
+    #[test]
+    fn bundled_scenes_load() {
+        for name in ["cornell.toml", "default.toml"] {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes").join(name);
+            if let Err(e) = load(&path, 16.0 / 9.0, None) {
+                panic!("{}: {:#}", name, e);
+            }
+        }
+    }
+}
@@ -1,43 +1,148 @@
+use crate::aabb::Aabb;
 use crate::hitable::{HitRecord, Hitable};
 use crate::material::Material;
+use crate::onb::Onb;
 use crate::ray::Ray;
-use glm::Vec3;
+use crate::sampler::Sampler;
+use glm::{vec2, vec3, Vec2, Vec3};
+use std::f32::consts::PI;
 use std::sync::Arc;
 
+// Longitude and latitude of a point on the unit sphere, with v running from
+// the bottom pole to the top.
+pub fn sphere_uv(p: &Vec3) -> Vec2 {
+    let theta = (-p.y).clamp(-1.0, 1.0).acos();
+    let phi = (-p.z).atan2(p.x) + PI;
+    vec2(phi / (2.0 * PI), theta / PI)
+}
+
 pub struct Sphere {
     pub centre: Vec3,
     pub radius: f32,
     pub material: Arc<dyn Material + Sync + Send>,
 }
 
+// Shared by still and moving spheres, which differ only in where the centre is.
+fn hit_sphere(
+    centre: Vec3,
+    radius: f32,
+    material: &Arc<dyn Material + Sync + Send>,
+    ray: &Ray,
+    t_min: f32,
+    t_max: f32,
+) -> Option<HitRecord> {
+    let oc = ray.origin - centre;
+    let a = glm::dot(&ray.direction, &ray.direction); // Squared length of a vector == dot of itself.
+    let b = glm::dot(&oc, &ray.direction);
+    let c = glm::dot(&oc, &oc) - radius * radius;
+    let d = b * b - a * c;
+
+    if d > 0.0 {
+        let temp = (-b - (b * b - a * c).sqrt()) / a;
+        if temp < t_max && temp > t_min {
+            let hit_point = ray.at(temp);
+            let normal = (1.0 / radius) * (hit_point - centre);
+            let outward_normal = (hit_point - centre) / radius;
+            let mut rec = HitRecord::new(temp, hit_point, normal, material.clone());
+            rec.set_face_normal(ray, &outward_normal);
+            rec.uv = sphere_uv(&outward_normal);
+            return Some(rec);
+        }
+        let temp = (-b + (b * b - a * c).sqrt()) / a;
+        if temp < t_max && temp > t_min {
+            let hit_point = ray.at(temp);
+            let normal = (1.0 / radius) * (hit_point - centre);
+            let mut rec = HitRecord::new(temp, hit_point, normal, material.clone());
+            let outward_normal = (hit_point - centre) / radius;
+            rec.set_face_normal(ray, &outward_normal);
+            rec.uv = sphere_uv(&outward_normal);
+            return Some(rec);
+        }
+    }
+    None
+}
+
 impl Hitable for Sphere {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let oc = ray.origin - self.centre;
-        let a = glm::dot(&ray.direction, &ray.direction); // Squared length of a vector == dot of itself.
-        let b = glm::dot(&oc, &ray.direction);
-        let c = glm::dot(&oc, &oc) - self.radius * self.radius;
-        let d = b * b - a * c;
-
-        if d > 0.0 {
-            let temp = (-b - (b * b - a * c).sqrt()) / a;
-            if temp < t_max && temp > t_min {
-                let hit_point = ray.at(temp);
-                let normal = (1.0 / self.radius) * (hit_point - self.centre);
-                let outward_normal = (hit_point - self.centre) / self.radius;
-                let mut rec = HitRecord::new(temp, hit_point, normal, self.material.clone());
-                rec.set_face_normal(ray, &outward_normal);
-                return Some(rec);
-            }
-            let temp = (-b + (b * b - a * c).sqrt()) / a;
-            if temp < t_max && temp > t_min {
-                let hit_point = ray.at(temp);
-                let normal = (1.0 / self.radius) * (hit_point - self.centre);
-                let mut rec = HitRecord::new(temp, hit_point, normal, self.material.clone());
-                let outward_normal = (hit_point - self.centre) / self.radius;
-                rec.set_face_normal(ray, &outward_normal);
-                return Some(rec);
-            }
+        hit_sphere(self.centre, self.radius, &self.material, ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self, _: f32, _: f32) -> Option<Aabb> {
+        let r = vec3(self.radius, self.radius, self.radius);
+        Some(Aabb::new(self.centre - r, self.centre + r))
+    }
+
+    fn lights<'a>(&'a self, lights: &mut Vec<&'a (dyn Hitable + Sync)>) {
+        if self.material.is_emissive() {
+            lights.push(self);
+        }
+    }
+
+    // Samples the cone the sphere subtends from outside it. There's no cone
+    // to sample from inside, so those points can only find it by chance.
+    fn pdf(&self, origin: &Vec3, direction: &Vec3) -> f32 {
+        let ray = Ray {
+            origin: *origin,
+            direction: *direction,
+            time: 0.0,
+            free_flight: 0.0,
+        };
+        if self.hit(&ray, 0.001, f32::MAX).is_none() {
+            return 0.0;
         }
-        None
+        let distance_squared = glm::length2(&(self.centre - origin));
+        if distance_squared <= self.radius * self.radius {
+            return 0.0;
+        }
+        let cos_theta_max = (1.0 - self.radius * self.radius / distance_squared).sqrt();
+        1.0 / (2.0 * PI * (1.0 - cos_theta_max))
+    }
+
+    fn sample_direction(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let direction = self.centre - origin;
+        let distance_squared = glm::length2(&direction);
+        if distance_squared <= self.radius * self.radius {
+            return direction;
+        }
+        let cos_theta_max = (1.0 - self.radius * self.radius / distance_squared).sqrt();
+        let u = sampler.next_2d();
+        let z = 1.0 + u.x * (cos_theta_max - 1.0);
+        let phi = 2.0 * PI * u.y;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        Onb::from_w(&direction).local(&vec3(phi.cos() * r, phi.sin() * r, z))
+    }
+}
+
+// Moves in a straight line from `centre0` at `time0` to `centre1` at
+// `time1`, carrying on at the same speed either side.
+pub struct MovingSphere {
+    pub centre0: Vec3,
+    pub centre1: Vec3,
+    pub time0: f32,
+    pub time1: f32,
+    pub radius: f32,
+    pub material: Arc<dyn Material + Sync + Send>,
+}
+
+impl MovingSphere {
+    pub fn centre(&self, time: f32) -> Vec3 {
+        if self.time1 == self.time0 {
+            return self.centre0;
+        }
+        self.centre0 + ((time - self.time0) / (self.time1 - self.time0)) * (self.centre1 - self.centre0)
+    }
+}
+
+impl Hitable for MovingSphere {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        hit_sphere(self.centre(ray.time), self.radius, &self.material, ray, t_min, t_max)
+    }
+
+    // The motion is linear, so the boxes at either end cover everything in
+    // between.
+    fn bounding_box(&self, time0: f32, time1: f32) -> Option<Aabb> {
+        let r = vec3(self.radius, self.radius, self.radius);
+        let (c0, c1) = (self.centre(time0), self.centre(time1));
+        Some(Aabb::new(c0 - r, c0 + r).union(&Aabb::new(c1 - r, c1 + r)))
     }
 }
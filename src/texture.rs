@@ -0,0 +1,123 @@
+use anyhow::{Context, Result};
+use glm::{vec3, Vec2, Vec3};
+use std::path::Path;
+use std::sync::Arc;
+
+pub trait Texture {
+    fn value(&self, uv: &Vec2, position: &Vec3) -> Vec3;
+}
+
+pub struct SolidColour {
+    pub colour: Vec3,
+}
+
+impl Texture for SolidColour {
+    fn value(&self, _: &Vec2, _: &Vec3) -> Vec3 {
+        self.colour
+    }
+}
+
+// Alternates between two textures in 3D cells of size `scale`, so it needs no
+// uvs and wraps around any shape.
+pub struct Checker {
+    pub even: Arc<dyn Texture + Sync + Send>,
+    pub odd: Arc<dyn Texture + Sync + Send>,
+    pub scale: f32,
+}
+
+impl Texture for Checker {
+    fn value(&self, uv: &Vec2, position: &Vec3) -> Vec3 {
+        let cell = position / self.scale;
+        let parity = cell.x.floor() as i64 + cell.y.floor() as i64 + cell.z.floor() as i64;
+        if parity.rem_euclid(2) == 0 {
+            self.even.value(uv, position)
+        } else {
+            self.odd.value(uv, position)
+        }
+    }
+}
+
+// How uvs outside [0, 1] map back onto an image.
+#[derive(Clone, Copy)]
+pub enum WrapMode {
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+impl WrapMode {
+    fn apply(&self, i: i64, size: usize) -> usize {
+        let n = size as i64;
+        let i = match self {
+            WrapMode::Repeat => i.rem_euclid(n),
+            WrapMode::Clamp => i.clamp(0, n - 1),
+            WrapMode::Mirror => {
+                let i = i.rem_euclid(2 * n);
+                if i < n {
+                    i
+                } else {
+                    2 * n - 1 - i
+                }
+            }
+        };
+        i as usize
+    }
+}
+
+// Bilinearly filtered image, stored as linear RGB.
+pub struct ImageTexture {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Vec3>,
+    pub wrap: WrapMode,
+}
+
+fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+impl ImageTexture {
+    // Loads a PNG or JPEG, assuming it's sRGB encoded.
+    pub fn load(path: &Path, wrap: WrapMode) -> Result<Self> {
+        let image = image::open(path)
+            .with_context(|| format!("Failed to load texture {}", path.display()))?
+            .to_rgb8();
+        let (width, height) = image.dimensions();
+        let pixels = image
+            .pixels()
+            .map(|p| vec3(srgb_to_linear(p[0]), srgb_to_linear(p[1]), srgb_to_linear(p[2])))
+            .collect();
+        Ok(Self {
+            width: width as usize,
+            height: height as usize,
+            pixels,
+            wrap,
+        })
+    }
+
+    fn texel(&self, x: i64, y: i64) -> Vec3 {
+        let x = self.wrap.apply(x, self.width);
+        let y = self.wrap.apply(y, self.height);
+        self.pixels[y * self.width + x]
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, uv: &Vec2, _: &Vec3) -> Vec3 {
+        // Images are stored top row first, but v runs upwards.
+        let x = uv.x * self.width as f32 - 0.5;
+        let y = (1.0 - uv.y) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let top = (1.0 - fx) * self.texel(x0, y0) + fx * self.texel(x0 + 1, y0);
+        let bottom = (1.0 - fx) * self.texel(x0, y0 + 1) + fx * self.texel(x0 + 1, y0 + 1);
+        (1.0 - fy) * top + fy * bottom
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::{bail, Error};
+use std::str::FromStr;
+
+// A rectangle of pixels rendered together, in pixels from the top left.
+#[derive(Clone, Copy, Debug)]
+pub struct Tile {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Tile {
+    // Indices of the tile's pixels in an image `image_width` across, a row
+    // at a time.
+    pub fn positions(&self, image_width: usize) -> impl Iterator<Item = usize> {
+        let Tile { x, y, width, height } = *self;
+        (y..y + height).flat_map(move |row| (x..x + width).map(move |column| row * image_width + column))
+    }
+
+    // The tile with `by` more pixels all the way round, as far as the image
+    // goes.
+    pub fn grow(&self, by: usize, image_width: usize, image_height: usize) -> Tile {
+        let (x, y) = (self.x.saturating_sub(by), self.y.saturating_sub(by));
+        Tile {
+            x,
+            y,
+            width: (self.x + self.width + by).min(image_width) - x,
+            height: (self.y + self.height + by).min(image_height) - y,
+        }
+    }
+}
+
+// The order tiles are handed out in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileOrder {
+    Scanline,
+    Spiral,
+    Hilbert,
+}
+
+impl FromStr for TileOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(match s {
+            "scanline" => TileOrder::Scanline,
+            "spiral" => TileOrder::Spiral,
+            "hilbert" => TileOrder::Hilbert,
+            _ => bail!("unknown tile order '{}', expected \"scanline\", \"spiral\" or \"hilbert\"", s),
+        })
+    }
+}
+
+// The point `d` steps along a Hilbert curve filling an `n` by `n` grid, `n`
+// being a power of two.
+fn hilbert_point(n: usize, d: usize) -> (usize, usize) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = d;
+    let mut s = 1;
+    while s < n {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x, y)
+}
+
+// Covers a `width` by `height` image in tiles `size` pixels square, or
+// smaller along the right and bottom edges. Scanline goes along each row of
+// tiles from the top, spiral starts in the middle and works outwards, and
+// Hilbert wanders so that each tile is next to the one before. That's only
+// quite true on a square grid a power of two across, as elsewhere the curve
+// is cut short at the edges and jumps between the pieces left.
+pub fn tiles(width: usize, height: usize, size: usize, order: TileOrder) -> Vec<Tile> {
+    let (columns, rows) = (width.div_ceil(size), height.div_ceil(size));
+    let mut cells: Vec<(usize, usize)> = (0..rows).flat_map(|r| (0..columns).map(move |c| (c, r))).collect();
+    match order {
+        TileOrder::Scanline => {}
+        TileOrder::Spiral => {
+            let centre = ((columns as f32 - 1.0) / 2.0, (rows as f32 - 1.0) / 2.0);
+            // Ring by ring, and round each ring by angle.
+            let key = |&(c, r): &(usize, usize)| {
+                let (dx, dy) = (c as f32 - centre.0, r as f32 - centre.1);
+                (dx.abs().max(dy.abs()), dy.atan2(dx))
+            };
+            cells.sort_by(|a, b| {
+                let (ka, kb) = (key(a), key(b));
+                ka.0.total_cmp(&kb.0).then(ka.1.total_cmp(&kb.1))
+            });
+        }
+        TileOrder::Hilbert => {
+            let n = columns.max(rows).next_power_of_two();
+            cells = (0..n * n)
+                .map(|d| hilbert_point(n, d))
+                .filter(|&(c, r)| c < columns && r < rows)
+                .collect();
+        }
+    }
+    cells
+        .into_iter()
+        .map(|(c, r)| Tile {
+            x: c * size,
+            y: r * size,
+            width: size.min(width - c * size),
+            height: size.min(height - r * size),
+        })
+        .collect()
+}
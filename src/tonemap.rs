@@ -0,0 +1,102 @@
+use crate::framebuffer::Framebuffer;
+use anyhow::{bail, Error};
+use glm::{vec3, Vec3};
+use std::str::FromStr;
+
+// How radiance is squeezed into the displayable range.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    // Anything over one is lost.
+    Clamp,
+    Reinhard,
+    // Narkowicz's fit of the ACES filmic curve.
+    Aces,
+}
+
+impl FromStr for Operator {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(match s {
+            "clamp" => Operator::Clamp,
+            "reinhard" => Operator::Reinhard,
+            "aces" => Operator::Aces,
+            _ => bail!("unknown tone mapping operator '{}', expected \"clamp\", \"reinhard\" or \"aces\"", s),
+        })
+    }
+}
+
+impl Operator {
+    fn apply(&self, c: f32) -> f32 {
+        match self {
+            Operator::Clamp => c,
+            Operator::Reinhard => c / (1.0 + c),
+            Operator::Aces => (c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14),
+        }
+    }
+}
+
+// The sRGB transfer function, from linear light to encoded values.
+pub fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Packs a display colour in [0, 1] for minifb.
+pub fn to_bgra(c: &Vec3) -> u32 {
+    let byte = |x: f32| (255.0 * x.clamp(0.0, 1.0) + 0.5) as u32;
+    255 << 24 | byte(c.x) << 16 | byte(c.y) << 8 | byte(c.z)
+}
+
+pub struct ToneMapper {
+    // In stops, so each one doubles the brightness.
+    pub exposure: f32,
+    pub operator: Operator,
+}
+
+impl ToneMapper {
+    // Display referred linear RGB in [0, 1].
+    pub fn map(&self, c: &Vec3) -> Vec3 {
+        let scale = self.exposure.exp2();
+        let channel = |x: f32| {
+            // A stray NaN shouldn't take out the whole pixel's neighbourhood
+            // once it's filtered or viewed, so it goes to black along with
+            // anything negative. Infinitely bright is as bright as it gets,
+            // though the curves would make it NaN too.
+            let x = (scale * x).max(0.0);
+            if x.is_infinite() {
+                1.0
+            } else {
+                self.operator.apply(x).clamp(0.0, 1.0)
+            }
+        };
+        vec3(channel(c.x), channel(c.y), channel(c.z))
+    }
+
+    // An sRGB encoded pixel, ready for the window or an 8-bit image.
+    pub fn display(&self, c: &Vec3) -> u32 {
+        let c = self.map(c);
+        to_bgra(&vec3(linear_to_srgb(c.x), linear_to_srgb(c.y), linear_to_srgb(c.z)))
+    }
+
+    pub fn to_bgra(&self, framebuffer: &Framebuffer) -> Vec<u32> {
+        framebuffer.pixels.iter().map(|c| self.display(c)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn infinity_is_white_and_nan_is_black() {
+        for operator in [Operator::Clamp, Operator::Reinhard, Operator::Aces] {
+            let mapper = ToneMapper { exposure: 0.0, operator };
+            let c = mapper.map(&vec3(f32::INFINITY, f32::NAN, f32::NEG_INFINITY));
+            assert_eq!(c, vec3(1.0, 0.0, 0.0), "{:?}", operator);
+        }
+    }
+}
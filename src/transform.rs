@@ -0,0 +1,232 @@
+use crate::aabb::Aabb;
+use crate::hitable::{HitRecord, Hitable};
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use glm::{vec3, vec4, Mat4, Quat, Vec3};
+use std::sync::Arc;
+
+pub fn transform_point(m: &Mat4, p: &Vec3) -> Vec3 {
+    (m * vec4(p.x, p.y, p.z, 1.0)).xyz()
+}
+
+pub fn transform_vector(m: &Mat4, v: &Vec3) -> Vec3 {
+    (m * vec4(v.x, v.y, v.z, 0.0)).xyz()
+}
+
+// Normals go through the inverse transpose, so they stay perpendicular to
+// the surface under non-uniform scaling. `inverse` is the inverse of the
+// transform being applied.
+pub fn transform_normal(inverse: &Mat4, n: &Vec3) -> Vec3 {
+    transform_vector(&inverse.transpose(), n).normalize()
+}
+
+// Scales, then rotates, then translates.
+pub fn compose(translation: &Vec3, rotation: &Quat, scale: &Vec3) -> Mat4 {
+    glm::translation(translation) * glm::quat_to_mat4(rotation) * glm::scaling(scale)
+}
+
+pub fn transform_aabb(m: &Mat4, b: &Aabb) -> Aabb {
+    b.corners().iter().fold(Aabb::empty(), |b, p| b.grow(&transform_point(m, p)))
+}
+
+// Intersects `object` as if it had been moved by `matrix`, by moving the ray
+// into object space instead and the hit back out. The direction isn't
+// renormalised, so distances along the ray are the same in both spaces.
+pub fn hit_transformed<T: Hitable + ?Sized>(
+    object: &T,
+    matrix: &Mat4,
+    inverse: &Mat4,
+    ray: &Ray,
+    t_min: f32,
+    t_max: f32,
+) -> Option<HitRecord> {
+    let local = Ray {
+        origin: transform_point(inverse, &ray.origin),
+        direction: transform_vector(inverse, &ray.direction),
+        time: ray.time,
+        free_flight: ray.free_flight,
+    };
+    let mut rec = object.hit(&local, t_min, t_max)?;
+    rec.position = transform_point(matrix, &rec.position);
+    // The normal still faces the ray, since transforming both keeps the sign
+    // of their dot product.
+    rec.normal = transform_normal(inverse, &rec.normal);
+    Some(rec)
+}
+
+// Spherical interpolation along the shorter arc, falling back to a
+// normalised lerp when the two are too close for the angle to be reliable.
+fn slerp(a: &Quat, b: &Quat, t: f32) -> Quat {
+    let mut b = *b;
+    let mut cos_theta = glm::quat_dot(a, &b);
+    if cos_theta < 0.0 {
+        b = -b;
+        cos_theta = -cos_theta;
+    }
+    if cos_theta > 0.9995 {
+        return glm::quat_normalize(&(a * (1.0 - t) + b * t));
+    }
+    let theta = cos_theta.acos();
+    (a * ((1.0 - t) * theta).sin() + b * (t * theta).sin()) / theta.sin()
+}
+
+#[derive(Clone, Copy)]
+pub struct Keyframe {
+    pub time: f32,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl Keyframe {
+    pub fn matrix(&self) -> Mat4 {
+        compose(&self.translation, &self.rotation, &self.scale)
+    }
+
+    pub fn inverse(&self) -> Mat4 {
+        let inv_scale = vec3(1.0 / self.scale.x, 1.0 / self.scale.y, 1.0 / self.scale.z);
+        glm::scaling(&inv_scale) * glm::quat_to_mat4(&glm::quat_conjugate(&self.rotation)) * glm::translation(&-self.translation)
+    }
+
+    fn lerp(&self, other: &Keyframe, time: f32) -> Keyframe {
+        let t = (time - self.time) / (other.time - self.time);
+        Keyframe {
+            time,
+            translation: glm::lerp(&self.translation, &other.translation, t),
+            rotation: slerp(&self.rotation, &other.rotation, t),
+            scale: glm::lerp(&self.scale, &other.scale, t),
+        }
+    }
+}
+
+// Moves another object through a list of keyframes, interpolating between
+// them and holding still before the first and after the last. Keyframes must
+// be sorted by time, and there must be at least one. Lights inside aren't
+// sampled directly, as light sampling doesn't know when it's happening and
+// so where they'd be.
+pub struct Animated {
+    pub object: Box<dyn Hitable + Sync + Send>,
+    pub keyframes: Vec<Keyframe>,
+}
+
+impl Animated {
+    pub fn at(&self, time: f32) -> Keyframe {
+        let next = self.keyframes.partition_point(|k| k.time <= time);
+        if next == 0 {
+            self.keyframes[0]
+        } else if next == self.keyframes.len() {
+            self.keyframes[next - 1]
+        } else {
+            self.keyframes[next - 1].lerp(&self.keyframes[next], time)
+        }
+    }
+}
+
+impl Hitable for Animated {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let k = self.at(ray.time);
+        hit_transformed(&self.object, &k.matrix(), &k.inverse(), ray, t_min, t_max)
+    }
+
+    // Bounds each stretch between keyframes in turn. Without rotation points
+    // move in straight lines and the boxes at either end are exact. With it,
+    // the box is swept by a sphere around the object's origin big enough to
+    // hold it in any orientation.
+    fn bounding_box(&self, time0: f32, time1: f32) -> Option<Aabb> {
+        let object = self.object.bounding_box(time0, time1)?;
+        let mut times = vec![time0];
+        times.extend(self.keyframes.iter().map(|k| k.time).filter(|&t| t > time0 && t < time1));
+        times.push(time1);
+
+        let mut bounds = Aabb::empty();
+        for pair in times.windows(2) {
+            let (a, b) = (self.at(pair[0]), self.at(pair[1]));
+            if glm::quat_dot(&a.rotation, &b.rotation).abs() > 1.0 - 1e-6 {
+                bounds = bounds
+                    .union(&transform_aabb(&a.matrix(), &object))
+                    .union(&transform_aabb(&b.matrix(), &object));
+            } else {
+                let scale = glm::max2(&glm::abs(&a.scale), &glm::abs(&b.scale));
+                let radius = object
+                    .corners()
+                    .iter()
+                    .map(|c| glm::length(&scale.component_mul(c)))
+                    .fold(0.0, f32::max);
+                let r = vec3(radius, radius, radius);
+                bounds = bounds
+                    .union(&Aabb::new(a.translation - r, a.translation + r))
+                    .union(&Aabb::new(b.translation - r, b.translation + r));
+            }
+        }
+        Some(bounds)
+    }
+}
+
+// Places shared geometry somewhere else, so one mesh can appear any number of
+// times while only being stored once. Any lights inside are sampled as one,
+// picking between them evenly.
+pub struct Instance {
+    object: Arc<dyn Hitable + Sync + Send>,
+    pub matrix: Mat4,
+    pub inverse: Mat4,
+    // The lights inside `object`, found once up front rather than every
+    // time one's sampled. They borrow from `object`, so it's kept private
+    // where it can't be swapped out from under them.
+    lights: Vec<&'static (dyn Hitable + Sync)>,
+}
+
+impl Instance {
+    // `matrix` must be invertible.
+    pub fn new(object: Arc<dyn Hitable + Sync + Send>, matrix: Mat4) -> Self {
+        // SAFETY: what the Arc points to doesn't move or change, and lives as
+        // long as `object` does, which is as long as the lights are kept.
+        let shared: &'static (dyn Hitable + Sync + Send) = unsafe { &*Arc::as_ptr(&object) };
+        let mut lights = Vec::new();
+        shared.lights(&mut lights);
+        Self {
+            object,
+            matrix,
+            inverse: glm::inverse(&matrix),
+            lights,
+        }
+    }
+}
+
+impl Hitable for Instance {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        hit_transformed(self.object.as_ref(), &self.matrix, &self.inverse, ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self, time0: f32, time1: f32) -> Option<Aabb> {
+        self.object.bounding_box(time0, time1).map(|b| transform_aabb(&self.matrix, &b))
+    }
+
+    fn lights<'a>(&'a self, lights: &mut Vec<&'a (dyn Hitable + Sync)>) {
+        if !self.lights.is_empty() {
+            lights.push(self);
+        }
+    }
+
+    // The density is worked out in object space, then scaled by how much
+    // the transform stretches solid angle around `direction`, which only
+    // matters when the scaling isn't uniform.
+    fn pdf(&self, origin: &Vec3, direction: &Vec3) -> f32 {
+        let lights = &self.lights;
+        if lights.is_empty() {
+            return 0.0;
+        }
+        let local_origin = transform_point(&self.inverse, origin);
+        let local_direction = transform_vector(&self.inverse, &direction.normalize());
+        let pdf = lights.iter().map(|l| l.pdf(&local_origin, &local_direction)).sum::<f32>() / lights.len() as f32;
+        let stretch = glm::determinant(&glm::mat4_to_mat3(&self.inverse)).abs() / local_direction.norm().powi(3);
+        pdf * stretch
+    }
+
+    fn sample_direction(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let lights = &self.lights;
+        let choice = (sampler.next_1d() * lights.len() as f32) as usize;
+        let light = lights[choice.min(lights.len() - 1)];
+        let local = light.sample_direction(&transform_point(&self.inverse, origin), sampler);
+        transform_vector(&self.matrix, &local)
+    }
+}
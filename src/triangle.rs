@@ -0,0 +1,113 @@
+use crate::aabb::Aabb;
+use crate::hitable::{HitRecord, Hitable};
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use glm::{vec2, Vec3};
+use std::sync::Arc;
+
+const EPSILON: f32 = 1e-8;
+
+// Möller–Trumbore intersection, returns the ray parameter and the barycentric
+// coordinates of the hit relative to p1 and p2.
+pub fn intersect(ray: &Ray, p0: &Vec3, p1: &Vec3, p2: &Vec3, t_min: f32, t_max: f32) -> Option<(f32, f32, f32)> {
+    let edge1 = p1 - p0;
+    let edge2 = p2 - p0;
+    let pvec = glm::cross(&ray.direction, &edge2);
+    let det = glm::dot(&edge1, &pvec);
+    if det.abs() < EPSILON {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+
+    let tvec = ray.origin - p0;
+    let b1 = glm::dot(&tvec, &pvec) * inv_det;
+    if !(0.0..=1.0).contains(&b1) {
+        return None;
+    }
+
+    let qvec = glm::cross(&tvec, &edge1);
+    let b2 = glm::dot(&ray.direction, &qvec) * inv_det;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return None;
+    }
+
+    let t = glm::dot(&edge2, &qvec) * inv_det;
+    if t < t_max && t > t_min {
+        Some((t, b1, b2))
+    } else {
+        None
+    }
+}
+
+pub fn area(p0: &Vec3, p1: &Vec3, p2: &Vec3) -> f32 {
+    0.5 * glm::cross(&(p1 - p0), &(p2 - p0)).norm()
+}
+
+// Uniformly distributed point on the triangle.
+pub fn sample_point(p0: &Vec3, p1: &Vec3, p2: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+    let u = sampler.next_2d();
+    let (su, r) = (u.x.sqrt(), u.y);
+    (1.0 - su) * p0 + (su * (1.0 - r)) * p1 + (su * r) * p2
+}
+
+// Converts an area density at the point `t` along the ray into a density
+// over solid angle.
+pub fn solid_angle_pdf(ray: &Ray, t: f32, normal: &Vec3, area: f32) -> f32 {
+    let length = ray.direction.norm();
+    let distance_squared = t * t * length * length;
+    let cosine = (glm::dot(&ray.direction, normal) / length).abs();
+    if cosine < 1e-6 {
+        return 0.0;
+    }
+    distance_squared / (cosine * area)
+}
+
+pub struct Triangle {
+    pub vertices: [Vec3; 3],
+    pub material: Arc<dyn Material + Sync + Send>,
+}
+
+impl Hitable for Triangle {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let [p0, p1, p2] = &self.vertices;
+        let (t, b1, b2) = intersect(ray, p0, p1, p2, t_min, t_max)?;
+        let outward_normal = glm::cross(&(p1 - p0), &(p2 - p0)).normalize();
+        let mut rec = HitRecord::new(t, ray.at(t), outward_normal, self.material.clone());
+        rec.set_face_normal(ray, &outward_normal);
+        rec.uv = vec2(b1, b2);
+        Some(rec)
+    }
+
+    fn bounding_box(&self, _: f32, _: f32) -> Option<Aabb> {
+        Some(Aabb::from_points(self.vertices.iter()))
+    }
+
+    fn lights<'a>(&'a self, lights: &mut Vec<&'a (dyn Hitable + Sync)>) {
+        if self.material.is_emissive() {
+            lights.push(self);
+        }
+    }
+
+    fn pdf(&self, origin: &Vec3, direction: &Vec3) -> f32 {
+        let [p0, p1, p2] = &self.vertices;
+        let ray = Ray {
+            origin: *origin,
+            direction: *direction,
+            time: 0.0,
+            free_flight: 0.0,
+        };
+        match intersect(&ray, p0, p1, p2, 0.001, f32::MAX) {
+            Some((t, _, _)) => {
+                let normal = glm::cross(&(p1 - p0), &(p2 - p0)).normalize();
+                solid_angle_pdf(&ray, t, &normal, area(p0, p1, p2))
+            }
+            None => 0.0,
+        }
+    }
+
+    fn sample_direction(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let [p0, p1, p2] = &self.vertices;
+        sample_point(p0, p1, p2, sampler) - origin
+    }
+}